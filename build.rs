@@ -1,5 +1,40 @@
 extern crate lalrpop;
 
+use std::env;
+use std::fs;
+use std::path::Path;
+
 fn main() {
     lalrpop::process_root().unwrap();
+    write_functions();
+}
+
+// Collects the built in function names from the grammar, where each is a
+// terminal like "median(", so the list used for suggestions can't drift from
+// what parses.
+fn write_functions() {
+    let grammar = fs::read_to_string("src/iqparser.lalrpop").unwrap();
+    let mut names: Vec<&str> = vec![];
+    for (i, _) in grammar.match_indices('"') {
+        let rest = &grammar[i + 1..];
+        let end = rest
+            .find(|c: char| !(c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_'))
+            .unwrap_or(rest.len());
+        let name = &rest[..end];
+        let is_call = rest[end..].starts_with("(\"") || rest[end..].starts_with("()\"");
+        if !name.is_empty() && is_call && !names.contains(&name) {
+            names.push(name);
+        }
+    }
+
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("functions.rs");
+    fs::write(
+        out,
+        format!(
+            "/// The functions built into the language.\npub const FUNCTIONS: [&str; {}] = {:?};\n",
+            names.len(),
+            names
+        ),
+    )
+    .unwrap();
 }
@@ -1,21 +1,54 @@
+use crate::builtins::{PixelBuiltin, ScalarBuiltin};
+use crate::context::{EdgeMode, PaletteOptions, ShapeEdge, StructuringElement};
+use crate::metadata::MetaField;
 use std::fmt::Debug;
 use std::option::Option;
 
+/// A range of byte offsets into the source of a script. Every node of the
+/// syntax tree records the span it was parsed from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+
+    /// The span from the start of this one to the end of `other`.
+    pub fn to(self, other: Span) -> Self {
+        Self::new(self.start, other.end)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct AttrAccessNode {
     pub key: String,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
 pub struct SelectorScalarNode {
     pub selector_ctx: SelectorCtxNode,
     pub accessed_attr: AttrAccessNode,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum TimeVar {
+    T(),
+    Frame(),
+    Frames(),
 }
 
 #[derive(Debug, Clone)]
 pub enum ScalarNode {
     Float(f64),
     Integer(i64),
+    Time(TimeVar),
+    Meta(MetaField),
     SelectorScalar(SelectorScalarNode),
     PixelScalar(Box<PixelExprType>, AttrAccessNode),
 }
@@ -46,25 +79,57 @@ pub struct PixelNode {
     pub g_expr: ScalarExprNode,
     pub b_expr: ScalarExprNode,
     pub a_expr: ScalarExprNode,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
 pub enum PixelExprType {
     Explicit(PixelNode),
-    CurrentPixel(),
+    CurrentPixel(Span),
     FnCall(PixelFnCall),
 }
 
+impl PixelExprType {
+    pub fn span(&self) -> Span {
+        match self {
+            Self::Explicit(pixel) => pixel.span,
+            Self::CurrentPixel(span) => *span,
+            Self::FnCall(call) => call.span,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum MatchComparisonValue {
     Scalar(ScalarExprNode),
     Pixel(PixelExprType),
 }
 
+impl MatchComparisonValue {
+    pub fn span(&self) -> Span {
+        match self {
+            Self::Scalar(expr) => expr.span(),
+            Self::Pixel(pixel) => pixel.span(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct MatchComparatorNode {
     pub op_type: MatchOpType,
     pub cmp_val: MatchComparisonValue,
+    /// Covers the whole comparison, including the value being compared.
+    pub span: Span,
+}
+
+impl MatchComparatorNode {
+    /// Extends the span back to the start of the value being compared.
+    pub fn starting_at(self, start: usize) -> Self {
+        Self {
+            span: Span::new(start, self.span.end),
+            ..self
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -73,25 +138,62 @@ pub enum MatchReturnValue {
     Operator(OperatorNode),
 }
 
+impl MatchReturnValue {
+    pub fn span(&self) -> Span {
+        match self {
+            Self::Pixel(pixel) => pixel.span(),
+            Self::Operator(op) => op.span(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum MorphOp {
+    Erode(),
+    Dilate(),
+    Open(),
+    Close(),
+}
+
+#[derive(Debug, Clone)]
+pub struct MorphologyNode {
+    pub op: MorphOp,
+    pub radius: ScalarExprNode,
+    pub element: StructuringElement,
+    pub span: Span,
+}
+
 #[derive(Debug, Clone)]
 pub struct MatchExprOpNode {
     pub match_value: MatchComparisonValue,
     pub match_comparator_node: Option<MatchComparatorNode>,
+    pub mask_morphology_nodes: Vec<MorphologyNode>,
     pub match_return_value_node: Box<MatchReturnValue>,
     pub else_return_value_node: Option<Box<MatchReturnValue>>,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
 pub enum OperatorNode {
-    UnaryNegationOp(),
+    UnaryNegationOp(Span),
     MatchExprOp(MatchExprOpNode),
 }
 
+impl OperatorNode {
+    pub fn span(&self) -> Span {
+        match self {
+            Self::UnaryNegationOp(span) => *span,
+            Self::MatchExprOp(op) => op.span,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct BinaryScalarOpNode {
     pub lhs: ScalarExprNode,
     pub op: BinaryOpType,
     pub rhs: ScalarExprNode,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
@@ -100,57 +202,237 @@ pub enum ScalarFnOp {
     Max(),
     Square(),
     Sqrt(),
+    Mean(),
+    Sum(),
+    StdDev(),
+    /// Random calls carry their position among the random calls of the
+    /// script, which picks their random stream so separate calls are
+    /// independent and layout changes don't change the values.
+    Rand(u64),
+    RandN(u64),
+    Noise2d(),
+    Custom(ScalarBuiltin),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Channels {
+    Rgb(),
+    Rgba(),
+}
+
+impl Channels {
+    pub fn includes_alpha(&self) -> bool {
+        *self == Channels::Rgba()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum PaletteSource {
+    /// The path of a `.gpl` file along with the colours read from it.
+    File(String, Vec<[f64; 3]>),
+    Colors(Vec<(ScalarExprNode, ScalarExprNode, ScalarExprNode)>),
+}
+
+/// An argument of `palette(...)` before it is sorted into the source and
+/// options.
+#[derive(Debug, Clone)]
+pub enum PaletteArg {
+    Color((ScalarExprNode, ScalarExprNode, ScalarExprNode)),
+    File(String, Span),
+    Option(String),
 }
 
 #[derive(Debug, Clone)]
 pub enum PixelFnOp {
     Center(),
-    Neighbors(i64, i64),
-    ColorScale(f64),
-    ColorAdd(),
+    Neighbors(ScalarExprNode, ScalarExprNode, Option<EdgeMode>),
+    Prev(Option<ScalarExprNode>),
+    ColorScale(Vec<ScalarExprNode>),
+    ColorAdd(Channels),
+    ColorSub(Channels),
+    ColorMul(Channels),
+    ColorMix(ScalarExprNode, Channels),
+    ColorClamp(ScalarExprNode, ScalarExprNode, Channels),
+    ColorAbs(),
     ColorNorm(),
-    AlphaBlend(f64),
+    AlphaBlend(ScalarExprNode),
+    BoxBlur(ScalarExprNode),
+    GaussianBlur(ScalarExprNode),
+    UnsharpMask(ScalarExprNode, ScalarExprNode),
+    Median(ScalarExprNode),
+    Bilateral(ScalarExprNode, ScalarExprNode),
+    Morphology(MorphologyNode),
+    Quantize(ScalarExprNode, PaletteOptions),
+    Palette(PaletteSource, PaletteOptions),
+    Custom(PixelBuiltin),
 }
 
 #[derive(Debug, Clone)]
 pub struct PixelFnCall {
     pub op: PixelFnOp,
     pub args: Vec<PixelExprType>,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
 pub struct ScalarFnCall {
     pub op: ScalarFnOp,
     pub args: Vec<ScalarExprNode>,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
 pub enum ScalarExprNode {
     ScalarFn(ScalarFnCall),
     SubExpr(Box<ScalarExprNode>),
-    Scalar(ScalarNode),
+    Scalar(ScalarNode, Span),
     BinaryOp(Box<BinaryScalarOpNode>),
 }
 
+impl ScalarExprNode {
+    pub fn span(&self) -> Span {
+        match self {
+            Self::ScalarFn(call) => call.span,
+            Self::SubExpr(expr) => expr.span(),
+            Self::Scalar(_, span) => *span,
+            Self::BinaryOp(op) => op.span,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum SliceBoundNode {
+    Index(ScalarExprNode),
+    Percent(ScalarExprNode),
+}
+
+impl SliceBoundNode {
+    pub fn span(&self) -> Span {
+        match self {
+            Self::Index(expr) | Self::Percent(expr) => expr.span(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SliceRangeNode {
-    pub lower_bound: Option<ScalarExprNode>,
-    pub upper_bound: Option<ScalarExprNode>,
+    pub lower_bound: Option<SliceBoundNode>,
+    pub upper_bound: Option<SliceBoundNode>,
+    pub step: Option<ScalarExprNode>,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
-pub struct SelectorCtxNode {
+pub struct SliceSelectorNode {
     pub y_slice_range: Option<Box<SliceRangeNode>>,
     pub x_slice_range: Option<Box<SliceRangeNode>>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum ShapeNode {
+    Circle(ScalarExprNode, ScalarExprNode, ScalarExprNode),
+    Ellipse(
+        ScalarExprNode,
+        ScalarExprNode,
+        ScalarExprNode,
+        ScalarExprNode,
+    ),
+    Polygon(Vec<(ScalarExprNode, ScalarExprNode)>),
+    Rect(
+        ScalarExprNode,
+        ScalarExprNode,
+        ScalarExprNode,
+        ScalarExprNode,
+    ),
+}
+
+#[derive(Debug, Clone)]
+pub enum SelectorCtxNode {
+    Slice(SliceSelectorNode),
+    Shape(Box<ShapeNode>, ShapeEdge, Span),
+    Mask(String, Span),
+}
+
+impl SelectorCtxNode {
+    pub fn span(&self) -> Span {
+        match self {
+            Self::Slice(slice) => slice.span,
+            Self::Shape(_, _, span) | Self::Mask(_, span) => *span,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct ExprNode {
     pub selector_ctx: Option<SelectorCtxNode>,
     pub op_nodes: Vec<OperatorNode>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub struct MaskConditionNode {
+    pub match_value: MatchComparisonValue,
+    pub match_comparator_node: MatchComparatorNode,
+    pub mask_morphology_nodes: Vec<MorphologyNode>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum MaskExprNode {
+    Condition(MaskConditionNode),
+    Named(String, Span),
+    Union(Box<MaskExprNode>, Box<MaskExprNode>),
+    Intersection(Box<MaskExprNode>, Box<MaskExprNode>),
+    Difference(Box<MaskExprNode>, Box<MaskExprNode>),
+    Invert(Box<MaskExprNode>, Span),
+    Grow(Box<MaskExprNode>, ScalarExprNode, StructuringElement, Span),
+    Shrink(Box<MaskExprNode>, ScalarExprNode, StructuringElement, Span),
+    Feather(Box<MaskExprNode>, ScalarExprNode, Span),
+}
+
+impl MaskExprNode {
+    pub fn span(&self) -> Span {
+        match self {
+            Self::Condition(condition) => condition.span,
+            Self::Named(_, span)
+            | Self::Invert(_, span)
+            | Self::Grow(_, _, _, span)
+            | Self::Shrink(_, _, _, span)
+            | Self::Feather(_, _, span) => *span,
+            Self::Union(l, r) | Self::Intersection(l, r) | Self::Difference(l, r) => {
+                l.span().to(r.span())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MaskDefNode {
+    pub name: String,
+    pub mask_expr: MaskExprNode,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum StatementNode {
+    Expr(ExprNode),
+    MaskDef(MaskDefNode),
+    Scalar(ScalarExprNode),
+}
+
+impl StatementNode {
+    pub fn span(&self) -> Span {
+        match self {
+            Self::Expr(expr) => expr.span,
+            Self::MaskDef(mask_def) => mask_def.span,
+            Self::Scalar(expr) => expr.span(),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct IqAstRootNode {
-    pub exprs: Vec<ExprNode>,
+    pub statements: Vec<StatementNode>,
+    pub span: Span,
 }
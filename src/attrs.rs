@@ -1,36 +1,111 @@
 use crate::context::*;
 
+/// The attributes of pixels, like `_.r`.
+pub const PIXEL_ATTRS: [&str; 13] = [
+    "y", "x", "ry", "rx", "v", "u", "radius", "angle", "lum", "r", "g", "b", "a",
+];
+
+/// The attributes of selections, like `[].w`.
+pub const SELECTION_ATTRS: [&str; 2] = ["h", "w"];
+
+/// The rows and columns covered by a selector, which relative attributes are
+/// measured against.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Region {
+    min: (u32, u32),
+    size: (u32, u32),
+}
+
+impl Region {
+    pub fn of<T>(ctx: &Context<T>) -> Self {
+        Self {
+            min: (ctx.y_bounds().0, ctx.x_bounds().0),
+            size: (ctx.height(), ctx.width()),
+        }
+    }
+
+    fn center(&self, bounds: SliceBounds) -> (f64, f64) {
+        (
+            (self.min.0 + bounds.size(self.size.0) / 2) as f64,
+            (self.min.1 + bounds.size(self.size.1) / 2) as f64,
+        )
+    }
+}
+
+fn per_pixel<F>(ctx: &AnnotatedPixelContext, f: F) -> AnnotatedFloatContext
+where
+    F: Fn(&IqPixel) -> f64,
+{
+    AnnotatedFloatContext::from_iter_with_annotation(ctx.iter_annotations(), |(pixel, annot)| {
+        (pixel.clone(), f(annot))
+    })
+}
+
+/// Normalizes an offset within an extent of `len` pixels to 0..1.
+fn normalize(offset: f64, len: u32) -> f64 {
+    if len > 1 {
+        offset / (len - 1) as f64
+    } else {
+        0.0
+    }
+}
+
+/// Reads an attribute of each pixel. Relative attributes (`rx`, `ry`, `u`,
+/// `v`, `radius` and `angle`) are measured against `region`, the pixels
+/// picked by the selector of the enclosing expression, even inside a match
+/// arm which only sees some of them.
 pub fn access_scalar_annotated_ctx_attr(
     ctx: &AnnotatedPixelContext,
+    region: Region,
+    bounds: SliceBounds,
     attr: &String,
 ) -> AnnotatedFloatContext {
+    let (min_y, min_x) = (region.min.0 as f64, region.min.1 as f64);
     if attr.eq_ignore_ascii_case("y") {
-        return AnnotatedFloatContext::from_iter_with_annotation(
-            ctx.iter_annotations(),
-            |(pixel, annot)| (pixel.clone(), annot.y as f64),
-        );
+        return per_pixel(ctx, |annot| annot.y as f64);
     } else if attr.eq_ignore_ascii_case("x") {
-        return AnnotatedFloatContext::from_iter_with_annotation(
-            ctx.iter_annotations(),
-            |(pixel, annot)| (pixel.clone(), annot.x as f64),
-        );
+        return per_pixel(ctx, |annot| annot.x as f64);
+    } else if attr.eq_ignore_ascii_case("ry") {
+        return per_pixel(ctx, |annot| annot.y as f64 - min_y);
+    } else if attr.eq_ignore_ascii_case("rx") {
+        return per_pixel(ctx, |annot| annot.x as f64 - min_x);
+    } else if attr.eq_ignore_ascii_case("v") {
+        let height = region.size.0;
+        return per_pixel(ctx, |annot| normalize(annot.y as f64 - min_y, height));
+    } else if attr.eq_ignore_ascii_case("u") {
+        let width = region.size.1;
+        return per_pixel(ctx, |annot| normalize(annot.x as f64 - min_x, width));
+    } else if attr.eq_ignore_ascii_case("radius") || attr.eq_ignore_ascii_case("angle") {
+        let (cy, cx) = region.center(bounds);
+        if attr.eq_ignore_ascii_case("radius") {
+            return per_pixel(ctx, |annot| {
+                (annot.y as f64 - cy).hypot(annot.x as f64 - cx)
+            });
+        }
+        // Degrees clockwise from the +x axis, since y grows downwards.
+        return per_pixel(ctx, |annot| {
+            (annot.y as f64 - cy)
+                .atan2(annot.x as f64 - cx)
+                .to_degrees()
+                .rem_euclid(360.0)
+        });
+    }
+    if attr.eq_ignore_ascii_case("lum") {
+        return per_pixel(ctx, |annot| luma(annot.c));
     }
     for (i, x) in ["r", "g", "b", "a"].iter().enumerate() {
         if attr.eq_ignore_ascii_case(x) {
-            return AnnotatedFloatContext::from_iter_with_annotation(
-                ctx.iter_annotations(),
-                |(pixel, annot)| (pixel.clone(), annot.c[i] as f64),
-            );
+            return per_pixel(ctx, |annot| annot.c[i] as f64);
         }
     }
     panic!("Unknown attribute: {:?}", attr)
 }
 
-pub fn access_scalar_attr<T>(ctx: &Context<T>, attr: &String) -> f64 {
+pub fn access_scalar_attr<T>(ctx: &Context<T>, attr: &String, bounds: SliceBounds) -> f64 {
     if attr.eq_ignore_ascii_case("h") {
-        ctx.height() as f64
+        bounds.size(ctx.height()) as f64
     } else if attr.eq_ignore_ascii_case("w") {
-        ctx.width() as f64
+        bounds.size(ctx.width()) as f64
     } else {
         panic!("Unknown attribute: {:?}", attr)
     }
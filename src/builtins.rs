@@ -0,0 +1,234 @@
+use crate::ast::{
+    MatchComparisonValue, PixelExprType, PixelFnCall, PixelFnOp, ScalarExprNode, ScalarFnCall,
+    ScalarFnOp, Span,
+};
+use crate::check::{did_you_mean, Diagnostic};
+use crate::context::AnnotatedPixelContext;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+pub type ScalarBuiltinFn = dyn Fn(&[f64]) -> f64;
+pub type PixelBuiltinFn = dyn Fn(&[AnnotatedPixelContext]) -> AnnotatedPixelContext;
+
+/// A named scalar function registered by a library user. It is called once
+/// per pixel with the evaluated values of its arguments. Calls are checked
+/// against `arity` when it is known.
+#[derive(Clone)]
+pub struct ScalarBuiltin {
+    pub name: String,
+    pub arity: Option<usize>,
+    pub f: Rc<ScalarBuiltinFn>,
+}
+
+/// A named pixel function registered by a library user. It is called once
+/// with the evaluated pixel contexts of its arguments. Calls are checked
+/// against `arity` when it is known.
+#[derive(Clone)]
+pub struct PixelBuiltin {
+    pub name: String,
+    pub arity: Option<usize>,
+    pub f: Rc<PixelBuiltinFn>,
+}
+
+impl fmt::Debug for ScalarBuiltin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ScalarBuiltin({:?})", self.name)
+    }
+}
+
+impl fmt::Debug for PixelBuiltin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PixelBuiltin({:?})", self.name)
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/functions.rs"));
+
+/// Registry of user provided functions which expressions can call by name.
+#[derive(Clone, Default)]
+pub struct Builtins {
+    scalar_fns: HashMap<String, ScalarBuiltin>,
+    pixel_fns: HashMap<String, PixelBuiltin>,
+}
+
+impl Builtins {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register_scalar<F>(&mut self, name: &str, f: F) -> &mut Self
+    where
+        F: Fn(&[f64]) -> f64 + 'static,
+    {
+        self.insert_scalar(name, None, Rc::new(f))
+    }
+
+    /// Registers a scalar function which must be called with exactly `arity`
+    /// arguments.
+    pub fn register_scalar_with_arity<F>(&mut self, name: &str, arity: usize, f: F) -> &mut Self
+    where
+        F: Fn(&[f64]) -> f64 + 'static,
+    {
+        self.insert_scalar(name, Some(arity), Rc::new(f))
+    }
+
+    pub fn register_pixel<F>(&mut self, name: &str, f: F) -> &mut Self
+    where
+        F: Fn(&[AnnotatedPixelContext]) -> AnnotatedPixelContext + 'static,
+    {
+        self.insert_pixel(name, None, Rc::new(f))
+    }
+
+    /// Registers a pixel function which must be called with exactly `arity`
+    /// arguments.
+    pub fn register_pixel_with_arity<F>(&mut self, name: &str, arity: usize, f: F) -> &mut Self
+    where
+        F: Fn(&[AnnotatedPixelContext]) -> AnnotatedPixelContext + 'static,
+    {
+        self.insert_pixel(name, Some(arity), Rc::new(f))
+    }
+
+    fn insert_scalar(
+        &mut self,
+        name: &str,
+        arity: Option<usize>,
+        f: Rc<ScalarBuiltinFn>,
+    ) -> &mut Self {
+        self.pixel_fns.remove(name);
+        self.scalar_fns.insert(
+            String::from(name),
+            ScalarBuiltin {
+                name: String::from(name),
+                arity,
+                f,
+            },
+        );
+        self
+    }
+
+    fn insert_pixel(
+        &mut self,
+        name: &str,
+        arity: Option<usize>,
+        f: Rc<PixelBuiltinFn>,
+    ) -> &mut Self {
+        self.scalar_fns.remove(name);
+        self.pixel_fns.insert(
+            String::from(name),
+            PixelBuiltin {
+                name: String::from(name),
+                arity,
+                f,
+            },
+        );
+        self
+    }
+
+    pub fn scalar(&self, name: &str) -> Option<&ScalarBuiltin> {
+        self.scalar_fns.get(name)
+    }
+
+    pub fn pixel(&self, name: &str) -> Option<&PixelBuiltin> {
+        self.pixel_fns.get(name)
+    }
+
+    fn unknown_function(&self, name: &str, span: Span) -> Diagnostic {
+        let mut names: Vec<&str> = self
+            .scalar_fns
+            .keys()
+            .chain(self.pixel_fns.keys())
+            .map(String::as_str)
+            .collect();
+        names.sort_unstable();
+        Diagnostic::new(span, format!("Unknown function: {:}()", name))
+            .with_hint(did_you_mean(name, FUNCTIONS.into_iter().chain(names)))
+    }
+
+    pub(crate) fn resolve_call(
+        &self,
+        name: &str,
+        args: Vec<MatchComparisonValue>,
+        span: Span,
+    ) -> Result<MatchComparisonValue, Diagnostic> {
+        if self.pixel(name).is_some() {
+            Ok(MatchComparisonValue::Pixel(PixelExprType::FnCall(
+                self.resolve_pixel_call(name, args, span)?,
+            )))
+        } else {
+            Ok(MatchComparisonValue::Scalar(ScalarExprNode::ScalarFn(
+                self.resolve_scalar_call(name, args, span)?,
+            )))
+        }
+    }
+
+    pub(crate) fn resolve_scalar_call(
+        &self,
+        name: &str,
+        args: Vec<MatchComparisonValue>,
+        span: Span,
+    ) -> Result<ScalarFnCall, Diagnostic> {
+        let builtin = match self.scalar(name) {
+            Some(builtin) => builtin.clone(),
+            None if self.pixel(name).is_some() => {
+                return Err(Diagnostic::new(
+                    span,
+                    format!("{:}() returns a pixel, not a scalar", name),
+                ))
+            }
+            None => return Err(self.unknown_function(name, span)),
+        };
+
+        let args = args
+            .into_iter()
+            .map(|arg| match arg {
+                MatchComparisonValue::Scalar(scalar_expr) => Ok(scalar_expr),
+                MatchComparisonValue::Pixel(pixel_expr) => Err(Diagnostic::new(
+                    pixel_expr.span(),
+                    format!("{:}() only accepts scalar arguments", name),
+                )),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(ScalarFnCall {
+            op: ScalarFnOp::Custom(builtin),
+            args,
+            span,
+        })
+    }
+
+    pub(crate) fn resolve_pixel_call(
+        &self,
+        name: &str,
+        args: Vec<MatchComparisonValue>,
+        span: Span,
+    ) -> Result<PixelFnCall, Diagnostic> {
+        let builtin = match self.pixel(name) {
+            Some(builtin) => builtin.clone(),
+            None if self.scalar(name).is_some() => {
+                return Err(Diagnostic::new(
+                    span,
+                    format!("{:}() returns a scalar, not a pixel", name),
+                ))
+            }
+            None => return Err(self.unknown_function(name, span)),
+        };
+
+        let args = args
+            .into_iter()
+            .map(|arg| match arg {
+                MatchComparisonValue::Pixel(pixel_expr) => Ok(pixel_expr),
+                MatchComparisonValue::Scalar(scalar_expr) => Err(Diagnostic::new(
+                    scalar_expr.span(),
+                    format!("{:}() only accepts pixel arguments", name),
+                )),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(PixelFnCall {
+            op: PixelFnOp::Custom(builtin),
+            args,
+            span,
+        })
+    }
+}
@@ -0,0 +1,636 @@
+//! Checks a parsed script before it is evaluated, so mistakes like comparing
+//! a pixel with a scalar or reading an unknown attribute are all reported up
+//! front rather than as a panic part way through an image.
+
+pub use crate::ast::Span;
+
+use crate::ast::*;
+use crate::attrs::{PIXEL_ATTRS, SELECTION_ATTRS};
+use crate::metadata::Metadata;
+use lalrpop_util::lexer::Token;
+use lalrpop_util::ParseError;
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+/// What an expression evaluates to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    Scalar(),
+    Pixel(),
+    Selection(),
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Scalar() => write!(f, "a scalar"),
+            Self::Pixel() => write!(f, "a pixel"),
+            Self::Selection() => write!(f, "a selection"),
+        }
+    }
+}
+
+/// A problem found in a script, with an optional hint on how to fix it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub message: String,
+    pub hint: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(span: Span, message: String) -> Self {
+        Self {
+            span,
+            message,
+            hint: None,
+        }
+    }
+
+    pub fn with_hint(self, hint: Option<String>) -> Self {
+        Self { hint, ..self }
+    }
+
+    pub(crate) fn from_parse_error(error: ParseError<usize, Token<'_>, Diagnostic>) -> Self {
+        match error {
+            ParseError::InvalidToken { location } => Self::new(
+                Span::new(location, location + 1),
+                String::from("Unrecognized token"),
+            ),
+            ParseError::UnrecognizedEOF { location, expected } => Self::new(
+                Span::new(location, location),
+                String::from("Unexpected end of script"),
+            )
+            .with_hint(expected_tokens(&expected)),
+            ParseError::UnrecognizedToken {
+                token: (start, token, end),
+                expected,
+            } => Self::new(Span::new(start, end), format!("Unexpected `{}`", token.1))
+                .with_hint(expected_tokens(&expected)),
+            ParseError::ExtraToken {
+                token: (start, token, end),
+            } => Self::new(Span::new(start, end), format!("Unexpected `{}`", token.1)),
+            ParseError::User { error } => error,
+        }
+    }
+
+    /// The 1-based line and column, counted in characters, where the
+    /// diagnostic starts.
+    pub fn line_col(&self, source: &str) -> (usize, usize) {
+        let before = &source[..self.span.start.min(source.len())];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        (
+            before.matches('\n').count() + 1,
+            before[line_start..].chars().count() + 1,
+        )
+    }
+
+    /// Renders the diagnostic with the line of `source` it points at and the
+    /// span underlined, in the style of rustc.
+    pub fn render(&self, source: &str) -> String {
+        let (line, column) = self.line_col(source);
+        let start = self.span.start.min(source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..]
+            .find('\n')
+            .map_or(source.len(), |i| start + i);
+        let underlined = source[start..self.span.end.clamp(start, line_end)]
+            .chars()
+            .count();
+
+        let gutter = " ".repeat(line.to_string().len());
+        let mut rendered = format!(
+            "error: {}\n{}--> {}:{}\n{} |\n{} | {}\n{} | {}{}",
+            self.message,
+            gutter,
+            line,
+            column,
+            gutter,
+            line,
+            &source[line_start..line_end],
+            gutter,
+            " ".repeat(column - 1),
+            "^".repeat(underlined.max(1)),
+        );
+        if let Some(hint) = &self.hint {
+            rendered.push_str(&format!("\n{} = help: {}", gutter, hint));
+        }
+        rendered
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}..{}: {}",
+            self.span.start, self.span.end, self.message
+        )?;
+        match &self.hint {
+            Some(hint) => write!(f, " ({})", hint),
+            None => Ok(()),
+        }
+    }
+}
+
+pub(crate) fn user_error<L, T>(span: Span, message: String) -> ParseError<L, T, Diagnostic> {
+    ParseError::User {
+        error: Diagnostic::new(span, message),
+    }
+}
+
+/// Parses a name like an edge mode, suggesting the closest of `names` when
+/// it isn't one of them.
+pub(crate) fn parse_name<'a, T, L, Tok>(
+    name: &str,
+    span: Span,
+    names: impl IntoIterator<Item = &'a str>,
+) -> Result<T, ParseError<L, Tok, Diagnostic>>
+where
+    T: FromStr<Err = String>,
+{
+    name.parse().map_err(|error| ParseError::User {
+        error: Diagnostic::new(span, error).with_hint(did_you_mean(name, names)),
+    })
+}
+
+// The parser describes tokens by their pattern, so calls and literals are
+// grouped into something more readable.
+fn expected_tokens(expected: &[String]) -> Option<String> {
+    let mut described: Vec<String> = vec![];
+    for token in expected {
+        let description = if token.starts_with("r#") {
+            if token.contains("\\(") {
+                String::from("a function call")
+            } else if token.contains("0-9]+") && !token.contains("a-z") {
+                String::from("a number")
+            } else if token.contains("[^") {
+                String::from("a string")
+            } else {
+                String::from("a name")
+            }
+        } else {
+            let literal = token.trim_matches('"');
+            if literal.len() > 1 && (literal.ends_with('(') || literal.ends_with("()")) {
+                String::from("a function call")
+            } else {
+                format!("`{}`", literal)
+            }
+        };
+        if !described.contains(&description) {
+            described.push(description);
+        }
+    }
+    match described.as_slice() {
+        [] => None,
+        [one] => Some(format!("expected {}", one)),
+        [init @ .., last] => Some(format!("expected {} or {}", init.join(", "), last)),
+    }
+}
+
+/// Suggests the candidate closest to `name`, if one is close enough to be a
+/// likely typo.
+pub(crate) fn did_you_mean<'a>(
+    name: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> Option<String> {
+    let threshold = (name.chars().count() / 3).max(1);
+    candidates
+        .into_iter()
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= threshold)
+        .min()
+        .map(|(_, candidate)| format!("did you mean `{}`?", candidate))
+}
+
+// The number of insertions, deletions, substitutions and swaps of adjacent
+// characters needed to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+/// Returns every problem found in the script, in the order they appear.
+/// Checks the tree of a script which will be run on an image with `metadata`.
+pub(crate) fn check(root: &IqAstRootNode, metadata: &Metadata) -> Vec<Diagnostic> {
+    let mut checker = Checker {
+        metadata,
+        masks: HashSet::new(),
+        later_masks: HashSet::new(),
+        diagnostics: vec![],
+    };
+    checker.root(root);
+    checker.diagnostics
+}
+
+// The value of an expression made only of numbers, if it is one.
+fn constant(expr: &ScalarExprNode) -> Option<f64> {
+    match expr {
+        ScalarExprNode::Scalar(ScalarNode::Float(n), _) => Some(*n),
+        ScalarExprNode::Scalar(ScalarNode::Integer(n), _) => Some(*n as f64),
+        ScalarExprNode::Scalar(_, _) => None,
+        ScalarExprNode::SubExpr(expr) => constant(expr),
+        ScalarExprNode::BinaryOp(op) => {
+            let (lhs, rhs) = (constant(&op.lhs)?, constant(&op.rhs)?);
+            Some(match op.op {
+                BinaryOpType::Add() => lhs + rhs,
+                BinaryOpType::Sub() => lhs - rhs,
+                BinaryOpType::Mul() => lhs * rhs,
+                BinaryOpType::Div() => lhs / rhs,
+            })
+        }
+        ScalarExprNode::ScalarFn(call) => {
+            let args = call.args.iter().map(constant).collect::<Option<Vec<_>>>()?;
+            match call.op {
+                ScalarFnOp::Min() => Some(args[0].min(args[1])),
+                ScalarFnOp::Max() => Some(args[0].max(args[1])),
+                ScalarFnOp::Square() => Some(args[0] * args[0]),
+                ScalarFnOp::Sqrt() => Some(args[0].sqrt()),
+                _ => None,
+            }
+        }
+    }
+}
+
+// Walks the tree tracking which masks have been defined. Scalar and pixel
+// expressions return whether their value can differ between pixels.
+struct Checker<'a> {
+    metadata: &'a Metadata,
+    masks: HashSet<String>,
+    later_masks: HashSet<String>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Checker<'_> {
+    fn error(&mut self, span: Span, message: String, hint: Option<String>) {
+        self.diagnostics
+            .push(Diagnostic::new(span, message).with_hint(hint))
+    }
+
+    fn root(&mut self, root: &IqAstRootNode) {
+        for statement in &root.statements {
+            if let StatementNode::MaskDef(mask_def) = statement {
+                self.later_masks.insert(mask_def.name.clone());
+            }
+        }
+        for statement in &root.statements {
+            match statement {
+                StatementNode::Expr(expr) => {
+                    if let Some(selector_ctx) = &expr.selector_ctx {
+                        self.selector(selector_ctx);
+                    }
+                    for op in &expr.op_nodes {
+                        self.operator(op);
+                    }
+                }
+                StatementNode::MaskDef(mask_def) => {
+                    self.mask(&mask_def.mask_expr);
+                    self.masks.insert(mask_def.name.clone());
+                }
+                StatementNode::Scalar(expr) => {
+                    if self.scalar(expr) {
+                        self.error(
+                            expr.span(),
+                            String::from("Scalar statements should have a single value"),
+                            Some(String::from("try an aggregate like `mean(...)`")),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    fn mask_name(&mut self, name: &str, span: Span) {
+        if self.masks.contains(name) {
+            return;
+        }
+        let hint = if self.later_masks.contains(name) {
+            Some(format!(
+                "`{}` is defined later, masks have to be defined before they are used",
+                name
+            ))
+        } else {
+            let mut masks: Vec<&str> = self.masks.iter().map(String::as_str).collect();
+            masks.sort_unstable();
+            did_you_mean(name, masks)
+        };
+        self.error(span, format!("Unknown mask: {:?}", name), hint);
+    }
+
+    fn attr(&mut self, attr: &AttrAccessNode, of: ValueType, known: &[&str]) {
+        if !known.contains(&attr.key.as_str()) {
+            let hint = did_you_mean(&attr.key, known.iter().copied())
+                .unwrap_or_else(|| format!("expected one of {}", known.join(", ")));
+            self.error(
+                attr.span,
+                format!("Unknown attribute of {}: {:?}", of, attr.key),
+                Some(hint),
+            );
+        }
+    }
+
+    fn arity(&mut self, name: &str, arity: Option<usize>, given: usize, span: Span) {
+        match arity {
+            Some(arity) if arity != given => self.error(
+                span,
+                format!(
+                    "{}() takes {} argument{} but {} were given",
+                    name,
+                    arity,
+                    if arity == 1 { "" } else { "s" },
+                    given
+                ),
+                None,
+            ),
+            _ => {}
+        }
+    }
+
+    fn selector(&mut self, selector_ctx: &SelectorCtxNode) {
+        match selector_ctx {
+            SelectorCtxNode::Slice(slice) => {
+                for range in [&slice.y_slice_range, &slice.x_slice_range]
+                    .into_iter()
+                    .flatten()
+                {
+                    for bound in [&range.lower_bound, &range.upper_bound]
+                        .into_iter()
+                        .flatten()
+                    {
+                        match bound {
+                            SliceBoundNode::Index(expr) | SliceBoundNode::Percent(expr) => {
+                                self.uniform(expr)
+                            }
+                        }
+                    }
+                    if let Some(step) = &range.step {
+                        self.uniform(step);
+                        match constant(step) {
+                            Some(value) if value.round() < 1.0 => self.error(
+                                step.span(),
+                                format!("Slice step should be at least 1, found {}", value),
+                                None,
+                            ),
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            SelectorCtxNode::Shape(shape, _, _) => {
+                let exprs: Vec<&ScalarExprNode> = match shape.as_ref() {
+                    ShapeNode::Circle(cy, cx, r) => vec![cy, cx, r],
+                    ShapeNode::Ellipse(cy, cx, ry, rx) => vec![cy, cx, ry, rx],
+                    ShapeNode::Rect(y0, x0, y1, x1) => vec![y0, x0, y1, x1],
+                    ShapeNode::Polygon(points) => points.iter().flat_map(|(y, x)| [y, x]).collect(),
+                };
+                for expr in exprs {
+                    self.uniform(expr);
+                }
+            }
+            SelectorCtxNode::Mask(name, span) => self.mask_name(name, *span),
+        }
+    }
+
+    fn operator(&mut self, op: &OperatorNode) {
+        match op {
+            OperatorNode::UnaryNegationOp(_) => {}
+            OperatorNode::MatchExprOp(match_expr) => {
+                self.comparison(
+                    &match_expr.match_value,
+                    match_expr.match_comparator_node.as_ref(),
+                    &match_expr.mask_morphology_nodes,
+                );
+                self.return_value(&match_expr.match_return_value_node);
+                if let Some(else_value) = &match_expr.else_return_value_node {
+                    self.return_value(else_value);
+                }
+            }
+        }
+    }
+
+    fn return_value(&mut self, value: &MatchReturnValue) {
+        match value {
+            MatchReturnValue::Pixel(pixel) => {
+                self.pixel(pixel);
+            }
+            MatchReturnValue::Operator(op) => self.operator(op),
+        }
+    }
+
+    fn comparison(
+        &mut self,
+        value: &MatchComparisonValue,
+        comparator: Option<&MatchComparatorNode>,
+        morphology: &[MorphologyNode],
+    ) {
+        let lhs = self.value(value);
+        if let Some(comparator) = comparator {
+            let rhs = self.value(&comparator.cmp_val);
+            if lhs != rhs {
+                self.error(
+                    comparator.span,
+                    format!("Can't compare {} with {}", lhs, rhs),
+                    Some(String::from(
+                        "compare an attribute of the pixel instead, like `_.r`",
+                    )),
+                );
+            }
+        }
+        for node in morphology {
+            self.uniform(&node.radius);
+        }
+    }
+
+    fn value(&mut self, value: &MatchComparisonValue) -> ValueType {
+        match value {
+            MatchComparisonValue::Scalar(expr) => {
+                self.scalar(expr);
+                ValueType::Scalar()
+            }
+            MatchComparisonValue::Pixel(pixel) => {
+                self.pixel(pixel);
+                ValueType::Pixel()
+            }
+        }
+    }
+
+    fn mask(&mut self, mask: &MaskExprNode) {
+        match mask {
+            MaskExprNode::Condition(condition) => self.comparison(
+                &condition.match_value,
+                Some(&condition.match_comparator_node),
+                &condition.mask_morphology_nodes,
+            ),
+            MaskExprNode::Named(name, span) => self.mask_name(name, *span),
+            MaskExprNode::Union(l, r)
+            | MaskExprNode::Intersection(l, r)
+            | MaskExprNode::Difference(l, r) => {
+                self.mask(l);
+                self.mask(r);
+            }
+            MaskExprNode::Invert(mask, _) => self.mask(mask),
+            MaskExprNode::Grow(mask, radius, _, _)
+            | MaskExprNode::Shrink(mask, radius, _, _)
+            | MaskExprNode::Feather(mask, radius, _) => {
+                self.mask(mask);
+                self.uniform(radius);
+            }
+        }
+    }
+
+    // Selectors, filters and mask operations are evaluated once for a whole
+    // context, so their parameters can't differ between pixels.
+    fn uniform(&mut self, expr: &ScalarExprNode) {
+        if self.scalar(expr) {
+            self.error(
+                expr.span(),
+                String::from("Parameter should be the same for every pixel"),
+                Some(String::from(
+                    "selectors, filters and masks are evaluated once per context, try an \
+                     aggregate like `mean(...)`",
+                )),
+            );
+        }
+    }
+
+    fn scalars<'a>(&mut self, exprs: impl IntoIterator<Item = &'a ScalarExprNode>) -> bool {
+        // Every expression is checked, even after one is found to vary.
+        exprs
+            .into_iter()
+            .fold(false, |varies, expr| self.scalar(expr) | varies)
+    }
+
+    fn scalar(&mut self, expr: &ScalarExprNode) -> bool {
+        match expr {
+            ScalarExprNode::ScalarFn(call) => {
+                let args_vary = self.scalars(&call.args);
+                match &call.op {
+                    ScalarFnOp::Mean() | ScalarFnOp::Sum() | ScalarFnOp::StdDev() => false,
+                    ScalarFnOp::Rand(_) | ScalarFnOp::RandN(_) => true,
+                    ScalarFnOp::Noise2d() => {
+                        let scale = &call.args[2];
+                        match constant(scale) {
+                            Some(value) if value <= 0.0 => self.error(
+                                scale.span(),
+                                format!("noise2d() scale should be positive, found {}", value),
+                                None,
+                            ),
+                            _ => {}
+                        }
+                        args_vary
+                    }
+                    ScalarFnOp::Custom(builtin) => {
+                        self.arity(&builtin.name, builtin.arity, call.args.len(), call.span);
+                        args_vary
+                    }
+                    _ => args_vary,
+                }
+            }
+            ScalarExprNode::SubExpr(expr) => self.scalar(expr),
+            ScalarExprNode::BinaryOp(op) => self.scalars([&op.lhs, &op.rhs]),
+            ScalarExprNode::Scalar(node, span) => match node {
+                ScalarNode::Float(_) | ScalarNode::Integer(_) | ScalarNode::Time(_) => false,
+                ScalarNode::Meta(field) => {
+                    if self.metadata.field(*field).is_none() {
+                        self.error(
+                            *span,
+                            format!("The input image has no {:?} metadata", field.name()),
+                            None,
+                        );
+                    }
+                    false
+                }
+                ScalarNode::SelectorScalar(selector_scalar) => {
+                    self.selector(&selector_scalar.selector_ctx);
+                    self.attr(
+                        &selector_scalar.accessed_attr,
+                        ValueType::Selection(),
+                        &SELECTION_ATTRS,
+                    );
+                    false
+                }
+                ScalarNode::PixelScalar(pixel, attr) => {
+                    let varies = self.pixel(pixel);
+                    self.attr(attr, ValueType::Pixel(), &PIXEL_ATTRS);
+                    varies
+                }
+            },
+        }
+    }
+
+    fn pixel(&mut self, pixel: &PixelExprType) -> bool {
+        match pixel {
+            PixelExprType::CurrentPixel(_) => true,
+            PixelExprType::Explicit(node) => self.scalars([
+                &node.y_expr,
+                &node.x_expr,
+                &node.r_expr,
+                &node.g_expr,
+                &node.b_expr,
+                &node.a_expr,
+            ]),
+            PixelExprType::FnCall(call) => {
+                let args_vary = call
+                    .args
+                    .iter()
+                    .fold(false, |varies, arg| self.pixel(arg) | varies);
+                // Parameters evaluated per pixel, and ones evaluated once.
+                let (params, uniform): (Vec<&ScalarExprNode>, Vec<&ScalarExprNode>) = match &call.op
+                {
+                    PixelFnOp::Center()
+                    | PixelFnOp::ColorAdd(_)
+                    | PixelFnOp::ColorSub(_)
+                    | PixelFnOp::ColorMul(_)
+                    | PixelFnOp::ColorAbs()
+                    | PixelFnOp::ColorNorm() => (vec![], vec![]),
+                    PixelFnOp::Neighbors(dy, dx, _) => (vec![dy, dx], vec![]),
+                    PixelFnOp::ColorScale(factors) => (factors.iter().collect(), vec![]),
+                    PixelFnOp::ColorMix(t, _) | PixelFnOp::AlphaBlend(t) => (vec![t], vec![]),
+                    PixelFnOp::ColorClamp(lower, upper, _) => (vec![lower, upper], vec![]),
+                    PixelFnOp::Prev(n) => (vec![], n.iter().collect()),
+                    PixelFnOp::BoxBlur(f)
+                    | PixelFnOp::GaussianBlur(f)
+                    | PixelFnOp::Median(f)
+                    | PixelFnOp::Quantize(f, _) => (vec![], vec![f]),
+                    PixelFnOp::UnsharpMask(a, b) | PixelFnOp::Bilateral(a, b) => {
+                        (vec![], vec![a, b])
+                    }
+                    PixelFnOp::Morphology(morphology) => (vec![], vec![&morphology.radius]),
+                    PixelFnOp::Palette(PaletteSource::Colors(colors), _) => (
+                        vec![],
+                        colors.iter().flat_map(|(r, g, b)| [r, g, b]).collect(),
+                    ),
+                    PixelFnOp::Palette(PaletteSource::File(_, _), _) => (vec![], vec![]),
+                    PixelFnOp::Custom(builtin) => {
+                        self.arity(&builtin.name, builtin.arity, call.args.len(), call.span);
+                        (vec![], vec![])
+                    }
+                };
+                for expr in uniform {
+                    self.uniform(expr);
+                }
+                let params_vary = self.scalars(params);
+                args_vary || params_vary
+            }
+        }
+    }
+}
@@ -1,7 +1,16 @@
-use image::RgbaImage;
+use crate::ctx_ops;
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::{AnimationDecoder, Delay, Frame, ImageFormat, RgbaImage};
+use regex::Regex;
 use std::cmp::{max, min, PartialOrd};
 use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Cursor, Write};
 use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
 
 #[derive(Debug, Clone, Hash, Eq, PartialEq, PartialOrd)]
 pub struct IqPixel {
@@ -15,7 +24,7 @@ impl IqPixel {
         Self {
             x: self.x,
             y: self.y,
-            c: [!self.c[0], !self.c[1], !self.c[2], !self.c[3]],
+            c: [255 - self.c[0], 255 - self.c[1], 255 - self.c[2], self.c[3]],
         }
     }
 
@@ -48,6 +57,719 @@ impl IqPixel {
     }
 }
 
+/// How channel values outside of `0..=255` are brought back into range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    Clamp(),
+    Wrap(),
+    // Rescales a channel linearly so its extremes fit, leaving in-range channels untouched.
+    Normalize(),
+}
+
+impl Default for OverflowPolicy {
+    fn default() -> Self {
+        Self::Clamp()
+    }
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("clamp") {
+            Ok(Self::Clamp())
+        } else if s.eq_ignore_ascii_case("wrap") {
+            Ok(Self::Wrap())
+        } else if s.eq_ignore_ascii_case("normalize") {
+            Ok(Self::Normalize())
+        } else {
+            Err(format!("Unknown overflow policy: {:?}", s))
+        }
+    }
+}
+
+impl OverflowPolicy {
+    pub fn apply(&self, colors: &mut [[i64; 4]]) {
+        match self {
+            Self::Clamp() => colors
+                .iter_mut()
+                .for_each(|c| *c = c.map(|v| v.clamp(0, 255))),
+            Self::Wrap() => colors
+                .iter_mut()
+                .for_each(|c| *c = c.map(|v| v.rem_euclid(256))),
+            Self::Normalize() => {
+                for i in 0..4 {
+                    let lower = colors.iter().map(|c| c[i]).fold(0, min);
+                    let upper = colors.iter().map(|c| c[i]).fold(255, max);
+                    if lower == 0 && upper == 255 {
+                        continue;
+                    }
+                    let scale = 255.0 / (upper - lower) as f64;
+                    for c in colors.iter_mut() {
+                        c[i] = ((c[i] - lower) as f64 * scale).round() as i64;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// How coordinates outside of a context are mapped back onto it when
+/// reading neighbouring pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeMode {
+    // Out of bounds pixels read as transparent black.
+    Zero(),
+    Clamp(),
+    Wrap(),
+    // Reflects about the edge pixel without repeating it.
+    Mirror(),
+}
+
+impl Default for EdgeMode {
+    fn default() -> Self {
+        Self::Clamp()
+    }
+}
+
+impl EdgeMode {
+    pub const NAMES: [&'static str; 4] = ["zero", "clamp", "wrap", "mirror"];
+}
+
+impl FromStr for EdgeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("zero") {
+            Ok(Self::Zero())
+        } else if s.eq_ignore_ascii_case("clamp") {
+            Ok(Self::Clamp())
+        } else if s.eq_ignore_ascii_case("wrap") {
+            Ok(Self::Wrap())
+        } else if s.eq_ignore_ascii_case("mirror") {
+            Ok(Self::Mirror())
+        } else {
+            Err(format!("Unknown edge mode: {:?}", s))
+        }
+    }
+}
+
+impl EdgeMode {
+    pub fn resolve(&self, i: i64, bounds: (u32, u32)) -> Option<u32> {
+        let (lower, upper) = (bounds.0 as i64, bounds.1 as i64);
+        if lower <= i && i <= upper {
+            return Some(i as u32);
+        }
+
+        let n = upper - lower + 1;
+        let resolved = match self {
+            Self::Zero() => return None,
+            Self::Clamp() => i.clamp(lower, upper),
+            Self::Wrap() => lower + (i - lower).rem_euclid(n),
+            Self::Mirror() if n == 1 => lower,
+            Self::Mirror() => {
+                let period = 2 * (n - 1);
+                let offset = (i - lower).rem_euclid(period);
+                lower + if offset < n { offset } else { period - offset }
+            }
+        };
+        Some(resolved as u32)
+    }
+}
+
+/// How the upper bound of a slice and the size of a context are counted.
+/// `HalfOpen` excludes the upper bound so `[0:10]` selects 10 rows and `[].h`
+/// is the number of rows. `Inclusive` keeps the behaviour of earlier versions
+/// where both bounds are included and sizes are one less than the pixel count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SliceBounds {
+    HalfOpen(),
+    Inclusive(),
+}
+
+impl Default for SliceBounds {
+    fn default() -> Self {
+        Self::HalfOpen()
+    }
+}
+
+impl SliceBounds {
+    pub fn exclusive_upper(&self, bound: u32) -> u32 {
+        match self {
+            Self::HalfOpen() => bound,
+            Self::Inclusive() => bound + 1,
+        }
+    }
+
+    pub fn size(&self, len: u32) -> u32 {
+        match self {
+            Self::HalfOpen() => len,
+            Self::Inclusive() => len.saturating_sub(1),
+        }
+    }
+}
+
+/// The neighbourhood shape used by morphological operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StructuringElement {
+    Square(),
+    Disk(),
+}
+
+impl Default for StructuringElement {
+    fn default() -> Self {
+        Self::Square()
+    }
+}
+
+impl StructuringElement {
+    pub const NAMES: [&'static str; 2] = ["square", "disk"];
+}
+
+impl FromStr for StructuringElement {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("square") {
+            Ok(Self::Square())
+        } else if s.eq_ignore_ascii_case("disk") {
+            Ok(Self::Disk())
+        } else {
+            Err(format!("Unknown structuring element: {:?}", s))
+        }
+    }
+}
+
+impl StructuringElement {
+    pub fn offsets(&self, radius: i64) -> Vec<(i64, i64)> {
+        let radius = radius.max(0);
+        (-radius..=radius)
+            .flat_map(|dy| (-radius..=radius).map(move |dx| (dy, dx)))
+            .filter(|(dy, dx)| match self {
+                Self::Square() => true,
+                Self::Disk() => dy * dy + dx * dx <= radius * radius,
+            })
+            .collect()
+    }
+}
+
+/// Whether a shape selects whole pixels or fades pixels on its boundary by how
+/// much of them it covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShapeEdge {
+    Hard(),
+    Smooth(),
+}
+
+impl Default for ShapeEdge {
+    fn default() -> Self {
+        Self::Hard()
+    }
+}
+
+impl ShapeEdge {
+    pub const NAMES: [&'static str; 2] = ["hard", "smooth"];
+}
+
+impl FromStr for ShapeEdge {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("hard") {
+            Ok(Self::Hard())
+        } else if s.eq_ignore_ascii_case("smooth") {
+            Ok(Self::Smooth())
+        } else {
+            Err(format!("Unknown shape edge: {:?}", s))
+        }
+    }
+}
+
+/// A region of the image plane in `(y, x)` coordinates, where each pixel
+/// covers the unit square centred on its location.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Shape {
+    Circle((f64, f64), f64),
+    Ellipse((f64, f64), (f64, f64)),
+    Polygon(Vec<(f64, f64)>),
+    Rect((f64, f64), (f64, f64)),
+}
+
+impl Shape {
+    pub fn contains(&self, y: f64, x: f64) -> bool {
+        match self {
+            Self::Circle((cy, cx), r) => (y - cy).powi(2) + (x - cx).powi(2) <= r * r,
+            Self::Ellipse((cy, cx), (ry, rx)) => {
+                ((y - cy) / ry).powi(2) + ((x - cx) / rx).powi(2) <= 1.0
+            }
+            // Even-odd rule, casting a ray towards increasing x
+            Self::Polygon(points) => {
+                let mut inside = false;
+                for (i, (y0, x0)) in points.iter().enumerate() {
+                    let (y1, x1) = points[(i + 1) % points.len()];
+                    if (*y0 > y) != (y1 > y) && x < x0 + (y - y0) * (x1 - x0) / (y1 - y0) {
+                        inside = !inside;
+                    }
+                }
+                inside
+            }
+            // Like slices the upper bounds are excluded, so the rect spans
+            // the pixels from `y0` up to but not including `y1`.
+            Self::Rect((y0, x0), (y1, x1)) => {
+                y0 - 0.5 <= y && y < y1 - 0.5 && x0 - 0.5 <= x && x < x1 - 0.5
+            }
+        }
+    }
+
+    /// The fraction of the pixel at `(y, x)` inside of the shape.
+    pub fn coverage(&self, y: u32, x: u32, edge: ShapeEdge) -> f64 {
+        const SAMPLES: u32 = 4;
+        let (y, x) = (y as f64, x as f64);
+        match edge {
+            ShapeEdge::Hard() => {
+                if self.contains(y, x) {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            ShapeEdge::Smooth() => {
+                let offset = |i: u32| (i as f64 + 0.5) / SAMPLES as f64 - 0.5;
+                let inside = (0..SAMPLES)
+                    .flat_map(|i| (0..SAMPLES).map(move |j| (offset(i), offset(j))))
+                    .filter(|(dy, dx)| self.contains(y + dy, x + dx))
+                    .count();
+                inside as f64 / (SAMPLES * SAMPLES) as f64
+            }
+        }
+    }
+}
+
+/// How colours are spread between palette entries when an image is reduced to
+/// a palette.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dither {
+    None(),
+    FloydSteinberg(),
+    Atkinson(),
+    Bayer(),
+}
+
+impl Default for Dither {
+    fn default() -> Self {
+        Self::None()
+    }
+}
+
+impl FromStr for Dither {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("none") {
+            Ok(Self::None())
+        } else if s.eq_ignore_ascii_case("floyd_steinberg") {
+            Ok(Self::FloydSteinberg())
+        } else if s.eq_ignore_ascii_case("atkinson") {
+            Ok(Self::Atkinson())
+        } else if s.eq_ignore_ascii_case("bayer") {
+            Ok(Self::Bayer())
+        } else {
+            Err(format!("Unknown dither: {:?}", s))
+        }
+    }
+}
+
+/// The colour space in which the nearest palette entry is chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorSpace {
+    Rgb(),
+    Lab(),
+}
+
+impl Default for ColorSpace {
+    fn default() -> Self {
+        Self::Rgb()
+    }
+}
+
+impl FromStr for ColorSpace {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("rgb") {
+            Ok(Self::Rgb())
+        } else if s.eq_ignore_ascii_case("lab") {
+            Ok(Self::Lab())
+        } else {
+            Err(format!("Unknown colour space: {:?}", s))
+        }
+    }
+}
+
+impl ColorSpace {
+    /// Converts an sRGB colour with channels in `0..255` to this space.
+    pub fn convert(&self, rgb: [f64; 3]) -> [f64; 3] {
+        match self {
+            Self::Rgb() => rgb,
+            Self::Lab() => {
+                let linear = rgb.map(|c| {
+                    let c = (c / 255.0).clamp(0.0, 1.0);
+                    if c <= 0.04045 {
+                        c / 12.92
+                    } else {
+                        ((c + 0.055) / 1.055).powf(2.4)
+                    }
+                });
+                let [r, g, b] = linear;
+                // XYZ relative to the D65 white point.
+                let xyz = [
+                    (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047,
+                    0.2126 * r + 0.7152 * g + 0.0722 * b,
+                    (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883,
+                ];
+                let delta: f64 = 6.0 / 29.0;
+                let [fx, fy, fz] = xyz.map(|t| {
+                    if t > delta.powi(3) {
+                        t.cbrt()
+                    } else {
+                        t / (3.0 * delta * delta) + 4.0 / 29.0
+                    }
+                });
+                [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+            }
+        }
+    }
+}
+
+/// The options shared by `quantize` and `palette`, given as trailing names
+/// in any order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PaletteOptions {
+    pub dither: Dither,
+    pub space: ColorSpace,
+}
+
+impl PaletteOptions {
+    pub fn from_names(names: &[String]) -> Result<Self, String> {
+        let mut options = Self::default();
+        for name in names {
+            if let Ok(dither) = name.parse() {
+                options.dither = dither;
+            } else if let Ok(space) = name.parse() {
+                options.space = space;
+            } else {
+                return Err(format!("Unknown palette option: {:?}", name));
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Text formats pixels can be written in instead of an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataFormat {
+    Json(),
+    Csv(),
+    Ndjson(),
+}
+
+impl FromStr for DataFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("json") {
+            Ok(Self::Json())
+        } else if s.eq_ignore_ascii_case("csv") {
+            Ok(Self::Csv())
+        } else if s.eq_ignore_ascii_case("ndjson") {
+            Ok(Self::Ndjson())
+        } else {
+            Err(format!("Unknown data format: {:?}", s))
+        }
+    }
+}
+
+/// Sample types of `.npy` arrays and raw pixel dumps. Integer samples cover
+/// their whole range and floats are expected to be in `0..1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleType {
+    U8(),
+    U16(),
+    F32(),
+}
+
+impl Default for SampleType {
+    fn default() -> Self {
+        Self::U8()
+    }
+}
+
+impl FromStr for SampleType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("uint8") || s.eq_ignore_ascii_case("u1") {
+            Ok(Self::U8())
+        } else if s.eq_ignore_ascii_case("uint16") || s.eq_ignore_ascii_case("u2") {
+            Ok(Self::U16())
+        } else if s.eq_ignore_ascii_case("float32") || s.eq_ignore_ascii_case("f4") {
+            Ok(Self::F32())
+        } else {
+            Err(format!("Unknown sample type: {:?}", s))
+        }
+    }
+}
+
+impl SampleType {
+    fn size(&self) -> usize {
+        match self {
+            Self::U8() => 1,
+            Self::U16() => 2,
+            Self::F32() => 4,
+        }
+    }
+
+    fn descr(&self) -> &'static str {
+        match self {
+            Self::U8() => "|u1",
+            Self::U16() => "<u2",
+            Self::F32() => "<f4",
+        }
+    }
+
+    fn read(&self, bytes: &[u8], big_endian: bool) -> i64 {
+        match self {
+            Self::U8() => bytes[0] as i64,
+            Self::U16() => {
+                let bytes = [bytes[0], bytes[1]];
+                let value = if big_endian {
+                    u16::from_be_bytes(bytes)
+                } else {
+                    u16::from_le_bytes(bytes)
+                };
+                (value as f64 / 257.0).round() as i64
+            }
+            Self::F32() => {
+                let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+                let value = if big_endian {
+                    f32::from_be_bytes(bytes)
+                } else {
+                    f32::from_le_bytes(bytes)
+                };
+                (value as f64 * 255.0).round() as i64
+            }
+        }
+    }
+
+    fn write(&self, channel: u8, out: &mut Vec<u8>) {
+        match self {
+            Self::U8() => out.push(channel),
+            Self::U16() => out.extend((channel as u16 * 257).to_le_bytes()),
+            Self::F32() => out.extend((channel as f32 / 255.0).to_le_bytes()),
+        }
+    }
+}
+
+/// The size and layout of a headerless pixel dump, written `HxW:<layout>`
+/// where the layout is `gray`, `rgb` or `rgba` followed by `8`, `16` or
+/// `f32`, for example `480x640:rgba8`. Samples are little endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawLayout {
+    pub h: u32,
+    pub w: u32,
+    pub channels: usize,
+    pub sample: SampleType,
+}
+
+impl FromStr for RawLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let re = Regex::new(r"^(\d+)x(\d+):(gray|rgba|rgb)(8|16|f32)$").unwrap();
+        let captures = re
+            .captures(s)
+            .ok_or_else(|| format!("Raw layout should look like HxW:rgba8, not {:?}", s))?;
+        let channels = match &captures[3] {
+            "gray" => 1,
+            "rgb" => 3,
+            _ => 4,
+        };
+        let sample = match &captures[4] {
+            "8" => SampleType::U8(),
+            "16" => SampleType::U16(),
+            _ => SampleType::F32(),
+        };
+        Ok(Self {
+            h: captures[1]
+                .parse()
+                .map_err(|_| format!("Bad height in {:?}", s))?,
+            w: captures[2]
+                .parse()
+                .map_err(|_| format!("Bad width in {:?}", s))?,
+            channels,
+            sample,
+        })
+    }
+}
+
+/// The netpbm formats. `P1` to `P3` are plain text and `P4` to `P6` binary
+/// versions of the bitmap, graymap and pixmap formats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Netpbm {
+    P1(),
+    P2(),
+    P3(),
+    P4(),
+    P5(),
+    P6(),
+}
+
+impl FromStr for Netpbm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("p1") {
+            Ok(Self::P1())
+        } else if s.eq_ignore_ascii_case("p2") {
+            Ok(Self::P2())
+        } else if s.eq_ignore_ascii_case("p3") {
+            Ok(Self::P3())
+        } else if s.eq_ignore_ascii_case("p4") {
+            Ok(Self::P4())
+        } else if s.eq_ignore_ascii_case("p5") {
+            Ok(Self::P5())
+        } else if s.eq_ignore_ascii_case("p6") {
+            Ok(Self::P6())
+        } else {
+            Err(format!("Unknown netpbm format: {:?}", s))
+        }
+    }
+}
+
+impl Netpbm {
+    fn magic(&self) -> &'static str {
+        match self {
+            Self::P1() => "P1",
+            Self::P2() => "P2",
+            Self::P3() => "P3",
+            Self::P4() => "P4",
+            Self::P5() => "P5",
+            Self::P6() => "P6",
+        }
+    }
+
+    fn channels(&self) -> usize {
+        match self {
+            Self::P3() | Self::P6() => 3,
+            _ => 1,
+        }
+    }
+
+    fn is_plain(&self) -> bool {
+        matches!(self, Self::P1() | Self::P2() | Self::P3())
+    }
+
+    fn is_bitmap(&self) -> bool {
+        matches!(self, Self::P1() | Self::P4())
+    }
+}
+
+/// Characters of the ASCII art format from white to black.
+pub const ASCII_RAMP: &str = " .:-=+*#%@";
+
+fn ascii_level(i: usize) -> i64 {
+    let steps = (ASCII_RAMP.len() - 1) as f64;
+    255 - (i as f64 * 255.0 / steps).round() as i64
+}
+
+/// The Rec. 709 luma of a colour.
+pub fn luma(c: [i64; 4]) -> f64 {
+    0.2126 * c[0] as f64 + 0.7152 * c[1] as f64 + 0.0722 * c[2] as f64
+}
+
+// Reads the whitespace separated header of netpbm and PFM files, where `#`
+// starts a comment running to the end of the line.
+struct HeaderReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> HeaderReader<'a> {
+    fn skip_space(&mut self) {
+        while let Some(&byte) = self.bytes.get(self.pos) {
+            if byte == b'#' {
+                while self.bytes.get(self.pos).is_some_and(|&byte| byte != b'\n') {
+                    self.pos += 1;
+                }
+            } else if byte.is_ascii_whitespace() {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn token(&mut self) -> Result<&'a str, String> {
+        self.skip_space();
+        let start = self.pos;
+        while self
+            .bytes
+            .get(self.pos)
+            .is_some_and(|byte| !byte.is_ascii_whitespace() && *byte != b'#')
+        {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err(String::from("Unexpected end of image header"));
+        }
+        std::str::from_utf8(&self.bytes[start..self.pos])
+            .map_err(|_| String::from("Image header should be ASCII"))
+    }
+
+    fn number<N: FromStr>(&mut self) -> Result<N, String> {
+        let token = self.token()?;
+        token
+            .parse()
+            .map_err(|_| format!("Expected a number in the image header, found {:?}", token))
+    }
+
+    // Binary data follows a single whitespace character after the header.
+    fn data(&self) -> &'a [u8] {
+        &self.bytes[min(self.pos + 1, self.bytes.len())..]
+    }
+}
+
+/// Reads the colours of a GIMP palette (`.gpl`) file.
+pub fn parse_gpl(contents: &str) -> Result<Vec<[f64; 3]>, String> {
+    let mut lines = contents.lines();
+    if lines.next().map(str::trim) != Some("GIMP Palette") {
+        return Err(String::from("Palette should start with 'GIMP Palette'"));
+    }
+    let mut colors = vec![];
+    for line in lines.map(str::trim) {
+        if line.is_empty()
+            || line.starts_with('#')
+            || line.starts_with("Name:")
+            || line.starts_with("Columns:")
+        {
+            continue;
+        }
+        let channels: Vec<f64> = line
+            .split_whitespace()
+            .take(3)
+            .map(|c| {
+                c.parse()
+                    .map_err(|_| format!("Bad palette line: {:?}", line))
+            })
+            .collect::<Result<_, _>>()?;
+        if channels.len() != 3 {
+            return Err(format!("Bad palette line: {:?}", line));
+        }
+        colors.push([channels[0], channels[1], channels[2]]);
+    }
+    Ok(colors)
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Context<T> {
     min_y: u32,
@@ -102,13 +824,13 @@ impl<T> Context<T> {
         }
     }
 
-    pub fn alpha_composite(contexts: Vec<Self>) -> Self {
+    pub fn alpha_composite(contexts: Vec<Self>, overflow: OverflowPolicy) -> Self {
         if contexts.is_empty() {
             Self::empty()
         } else {
             let mut out = Self::empty();
             for ctx in contexts {
-                for pixel in ctx.iter() {
+                for pixel in ctx.saturated_pixels(overflow).iter() {
                     if let Some(under_pixel) = out.pixels.get(&(pixel.y, pixel.x)) {
                         out.insert(under_pixel.alpha_composite(pixel))
                     } else {
@@ -120,13 +842,78 @@ impl<T> Context<T> {
         }
     }
 
+    fn saturated_pixels(&self, overflow: OverflowPolicy) -> Vec<IqPixel> {
+        let mut pixels: Vec<IqPixel> = self.iter().cloned().collect();
+        let mut colors: Vec<[i64; 4]> = pixels.iter().map(|pixel| pixel.c).collect();
+        overflow.apply(&mut colors);
+        for (pixel, c) in pixels.iter_mut().zip(colors) {
+            pixel.c = c;
+        }
+        pixels
+    }
+
+    pub fn with_overflow(&self, overflow: OverflowPolicy) -> Self {
+        Self::from_iter(self.saturated_pixels(overflow), |pixel| pixel)
+    }
+
     pub fn write(&self, path: &str) {
-        let mut img = RgbaImage::new(self.max_y + 1, self.max_x + 1);
+        self.write_with_overflow(path, OverflowPolicy::default())
+    }
 
-        for pixel in self.iter() {
+    /// Writes the image on a canvas reaching from the origin to its bottom
+    /// right pixel. Pixels keep their position, so a selection is written in
+    /// place with transparent pixels around it, see `cropped`.
+    pub fn write_with_overflow(&self, path: &str, overflow: OverflowPolicy) {
+        self.to_image(overflow, (self.max_y + 1, self.max_x + 1))
+            .save(path)
+            .unwrap();
+    }
+
+    /// Writes one record of `y, x, r, g, b, a` per pixel in raster order.
+    pub fn write_data<W: Write>(
+        &self,
+        out: &mut W,
+        format: DataFormat,
+        overflow: OverflowPolicy,
+    ) -> io::Result<()> {
+        let mut pixels = self.saturated_pixels(overflow);
+        pixels.sort_by_key(|pixel| (pixel.y, pixel.x));
+        let object = |pixel: &IqPixel| {
+            format!(
+                "{{\"y\":{},\"x\":{},\"r\":{},\"g\":{},\"b\":{},\"a\":{}}}",
+                pixel.y, pixel.x, pixel.c[0], pixel.c[1], pixel.c[2], pixel.c[3]
+            )
+        };
+        match format {
+            DataFormat::Csv() => {
+                writeln!(out, "y,x,r,g,b,a")?;
+                for pixel in &pixels {
+                    let [r, g, b, a] = pixel.c;
+                    writeln!(out, "{},{},{},{},{},{}", pixel.y, pixel.x, r, g, b, a)?;
+                }
+            }
+            DataFormat::Json() => {
+                let objects: Vec<String> = pixels.iter().map(object).collect();
+                writeln!(out, "[{}]", objects.join(","))?;
+            }
+            DataFormat::Ndjson() => {
+                for pixel in &pixels {
+                    writeln!(out, "{}", object(pixel))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Pixels keep their position, so a selection is written in place on a
+    // transparent canvas rather than cropped.
+    fn to_image(&self, overflow: OverflowPolicy, (h, w): (u32, u32)) -> RgbaImage {
+        let mut img = RgbaImage::new(w, h);
+
+        for pixel in self.saturated_pixels(overflow).iter() {
             img.put_pixel(
-                pixel.x - self.min_x,
-                pixel.y - self.min_y,
+                pixel.x,
+                pixel.y,
                 image::Rgba([
                     pixel.c[0] as u8,
                     pixel.c[1] as u8,
@@ -136,17 +923,108 @@ impl<T> Context<T> {
             )
         }
 
-        img.save(path).unwrap();
+        img
     }
 
-    pub fn from_path(path: &str) -> Self {
-        let img = image::open(&Path::new(path)).unwrap().to_rgba8();
+    /// Writes frames as an animated `.gif` or `.png` (APNG) played at `fps`,
+    /// or as numbered images when the path contains a pattern like `%04d`.
+    pub fn write_frames(
+        frames: &[Self],
+        path: &str,
+        fps: f64,
+        overflow: OverflowPolicy,
+    ) -> Vec<String> {
+        let delays = vec![Duration::from_secs_f64(1.0 / fps); frames.len()];
+        Self::write_animation(frames, &delays, 0, path, overflow)
+    }
+
+    /// Writes frames shown for the given delays, played `plays` times or
+    /// forever when `plays` is 0. Frames share a canvas large enough for all
+    /// of them. Returns the paths written, which are one per frame for
+    /// numbered frames.
+    pub fn write_animation(
+        frames: &[Self],
+        delays: &[Duration],
+        plays: u32,
+        path: &str,
+        overflow: OverflowPolicy,
+    ) -> Vec<String> {
+        let size = frames.iter().fold((1, 1), |(h, w), frame| {
+            (max(h, frame.max_y + 1), max(w, frame.max_x + 1))
+        });
+        let images: Vec<RgbaImage> = frames
+            .iter()
+            .map(|frame| frame.to_image(overflow, size))
+            .collect();
+
+        let numbered = Regex::new(r"%(0?)(\d*)d").unwrap();
+        if let Some(captures) = numbered.captures(path) {
+            let width: usize = captures[2].parse().unwrap_or(0);
+            let mut paths = vec![];
+            for (i, img) in images.iter().enumerate() {
+                let number = if &captures[1] == "0" {
+                    format!("{:0width$}", i, width = width)
+                } else {
+                    format!("{:width$}", i, width = width)
+                };
+                let frame_path = numbered.replace(path, number.as_str()).into_owned();
+                img.save(&frame_path).unwrap();
+                paths.push(frame_path);
+            }
+            return paths;
+        }
+
+        let file = BufWriter::new(File::create(path).unwrap());
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        match extension.as_str() {
+            "gif" => {
+                let mut encoder = GifEncoder::new(file);
+                // GIFs count repeats after the first play, and play once
+                // without a count.
+                match plays {
+                    0 => encoder.set_repeat(Repeat::Infinite).unwrap(),
+                    1 => (),
+                    n => encoder.set_repeat(Repeat::Finite((n - 1) as u16)).unwrap(),
+                }
+                encoder
+                    .encode_frames(images.into_iter().zip(delays).map(|(img, delay)| {
+                        Frame::from_parts(img, 0, 0, Delay::from_saturating_duration(*delay))
+                    }))
+                    .unwrap();
+            }
+            "png" | "apng" => {
+                let mut encoder = png::Encoder::new(file, size.1, size.0);
+                encoder.set_color(png::ColorType::Rgba);
+                encoder.set_depth(png::BitDepth::Eight);
+                encoder.set_animated(images.len() as u32, plays).unwrap();
+                let mut writer = encoder.write_header().unwrap();
+                for (img, delay) in images.iter().zip(delays) {
+                    writer
+                        .set_frame_delay(delay.as_millis().min(u16::MAX as u128) as u16, 1000)
+                        .unwrap();
+                    writer.write_image_data(img.as_raw()).unwrap();
+                }
+                writer.finish().unwrap();
+            }
+            _ => panic!(
+                "Animations can be written as .gif, .png or numbered frames (ex. 'out_%04d.png'), not {:?}",
+                path
+            ),
+        }
+        vec![String::from(path)]
+    }
+
+    fn from_image(img: &RgbaImage, (top, left): (u32, u32)) -> Self {
         let mut out = Self::empty();
 
         for (x, y, c) in img.enumerate_pixels() {
             out.insert(IqPixel {
-                y,
-                x,
+                y: top + y,
+                x: left + x,
                 c: [c[0] as i64, c[1] as i64, c[2] as i64, c[3] as i64],
             })
         }
@@ -154,38 +1032,480 @@ impl<T> Context<T> {
         out
     }
 
+    pub fn from_path(path: &str) -> Self {
+        Self::from_image(&image::open(Path::new(path)).unwrap().to_rgba8(), (0, 0))
+    }
+
+    // Reads H x W x C interleaved samples. One channel is grey, two are grey
+    // and alpha, three are RGB and four are RGBA.
+    fn from_samples(
+        data: &[u8],
+        (h, w, channels): (u32, u32, usize),
+        sample: SampleType,
+        big_endian: bool,
+    ) -> Result<Self, String> {
+        if !(1..=4).contains(&channels) {
+            return Err(format!("Expected 1 to 4 channels, not {}", channels));
+        }
+        let stride = channels * sample.size();
+        let expected = (h as usize)
+            .checked_mul(w as usize)
+            .and_then(|pixels| pixels.checked_mul(stride))
+            .ok_or(format!("An array of {}x{} pixels is too large", h, w))?;
+        if data.len() != expected {
+            return Err(format!(
+                "Expected {} bytes of pixel data, found {}",
+                expected,
+                data.len()
+            ));
+        }
+
+        let mut out = Self::empty();
+        for (i, chunk) in data.chunks_exact(stride).enumerate() {
+            let values: Vec<i64> = chunk
+                .chunks_exact(sample.size())
+                .map(|bytes| sample.read(bytes, big_endian))
+                .collect();
+            let c = match values[..] {
+                [v] => [v, v, v, 255],
+                [v, a] => [v, v, v, a],
+                [r, g, b] => [r, g, b, 255],
+                [r, g, b, a] => [r, g, b, a],
+                _ => unreachable!(),
+            };
+            out.insert(IqPixel {
+                y: i as u32 / w,
+                x: i as u32 % w,
+                c,
+            });
+        }
+        Ok(out)
+    }
+
+    /// Reads a headerless dump of pixels in the given layout.
+    pub fn from_raw(data: &[u8], layout: RawLayout) -> Result<Self, String> {
+        Self::from_samples(
+            data,
+            (layout.h, layout.w, layout.channels),
+            layout.sample,
+            false,
+        )
+    }
+
+    /// Reads a numpy array of uint8, uint16 or float32 samples with shape
+    /// `(H, W)` or `(H, W, C)`.
+    pub fn from_npy_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if !bytes.starts_with(NPY_MAGIC) || bytes.len() < 10 {
+            return Err(String::from("Not a .npy file"));
+        }
+        let (header_len, header_start) = match bytes[6] {
+            1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+            2 | 3 if bytes.len() >= 12 => (
+                u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize,
+                12,
+            ),
+            version => return Err(format!("Unsupported .npy version: {}", version)),
+        };
+        let header = bytes
+            .get(header_start..header_start + header_len)
+            .map(String::from_utf8_lossy)
+            .ok_or("Truncated .npy header")?;
+
+        let field = |name: &str, pattern: &str| {
+            Regex::new(&format!(r"'{}':\s*{}", name, pattern))
+                .unwrap()
+                .captures(&header)
+                .map(|captures| captures[1].to_string())
+                .ok_or(format!("Missing {:?} in .npy header", name))
+        };
+        if field("fortran_order", r"(True|False)")? == "True" {
+            return Err(String::from("Fortran ordered arrays are not supported"));
+        }
+        let descr = field("descr", r"'([^']*)'")?;
+        // A byte order character like `<`, then the sample type.
+        let (order, kind) = match (descr.get(..1), descr.get(1..)) {
+            (Some(order), Some(kind)) => (order, kind),
+            _ => return Err(format!("Bad .npy descr {:?}", descr)),
+        };
+        let sample: SampleType = kind.parse()?;
+        let big_endian = order == ">";
+        let shape: Vec<usize> = field("shape", r"\(([^)]*)\)")?
+            .split(',')
+            .map(str::trim)
+            .filter(|dim| !dim.is_empty())
+            .map(|dim| {
+                dim.parse()
+                    .map_err(|_| format!("Bad shape dimension {:?}", dim))
+            })
+            .collect::<Result<_, _>>()?;
+        let (h, w, c) = match shape[..] {
+            [h, w] => (h, w, 1),
+            [h, w, c] => (h, w, c),
+            _ => return Err(format!("Expected an (H, W, C) array, not {:?}", shape)),
+        };
+        let dim = |len: usize| {
+            u32::try_from(len).map_err(|_| format!("Array dimension {} is too large", len))
+        };
+        let dims = (dim(h)?, dim(w)?, c);
+
+        Self::from_samples(
+            &bytes[header_start + header_len..],
+            dims,
+            sample,
+            big_endian,
+        )
+    }
+
+    pub fn from_npy(path: &str) -> Self {
+        Self::from_npy_bytes(&fs::read(path).unwrap()).unwrap()
+    }
+
+    /// Encodes the pixels as an `(H, W, 4)` numpy array. Like images, the
+    /// array covers the canvas up to the bottom right pixel and positions
+    /// without a pixel are zero.
+    pub fn to_npy_bytes(&self, sample: SampleType, overflow: OverflowPolicy) -> Vec<u8> {
+        let img = self.to_image(overflow, (self.max_y + 1, self.max_x + 1));
+        let mut header = format!(
+            "{{'descr': '{}', 'fortran_order': False, 'shape': ({}, {}, 4), }}",
+            sample.descr(),
+            img.height(),
+            img.width()
+        );
+        // The header is padded so the data starts on a 64 byte boundary.
+        let unpadded = NPY_MAGIC.len() + 4 + header.len() + 1;
+        header.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+        header.push('\n');
+
+        let mut out = NPY_MAGIC.to_vec();
+        out.extend([1, 0]);
+        out.extend((header.len() as u16).to_le_bytes());
+        out.extend(header.as_bytes());
+        for channel in img.as_raw() {
+            sample.write(*channel, &mut out);
+        }
+        out
+    }
+
+    pub fn write_npy(&self, path: &str, sample: SampleType, overflow: OverflowPolicy) {
+        fs::write(path, self.to_npy_bytes(sample, overflow)).unwrap()
+    }
+
+    /// Reads any of the netpbm formats, scaling samples by their maximum
+    /// value. In bitmaps 1 is black.
+    pub fn from_netpbm_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let mut header = HeaderReader { bytes, pos: 0 };
+        let format: Netpbm = header.token()?.parse()?;
+        let w: u32 = header.number()?;
+        let h: u32 = header.number()?;
+        let maxval: u32 = if format.is_bitmap() {
+            1
+        } else {
+            header.number()?
+        };
+        if maxval == 0 || maxval > u16::MAX as u32 {
+            return Err(format!("Bad maximum value: {}", maxval));
+        }
+
+        let count = h as usize * w as usize * format.channels();
+        let samples: Vec<u32> = match format {
+            Netpbm::P1() => {
+                // Plain bitmap digits don't need to be separated.
+                header.skip_space();
+                let digits: Vec<u32> = header.bytes[header.pos..]
+                    .iter()
+                    .filter(|byte| !byte.is_ascii_whitespace())
+                    .map(|byte| match byte {
+                        b'0' => Ok(0),
+                        b'1' => Ok(1),
+                        _ => Err(format!("Unexpected {:?} in bitmap", *byte as char)),
+                    })
+                    .collect::<Result<_, _>>()?;
+                digits.into_iter().take(count).collect()
+            }
+            Netpbm::P2() | Netpbm::P3() => (0..count)
+                .map(|_| header.number())
+                .collect::<Result<_, _>>()
+                .map_err(|_| String::from("Too few samples in image"))?,
+            Netpbm::P4() => {
+                let row_bytes = (w as usize).div_ceil(8);
+                let data = header.data();
+                (0..h as usize)
+                    .flat_map(|y| (0..w as usize).map(move |x| (y, x)))
+                    .map_while(|(y, x)| {
+                        data.get(y * row_bytes + x / 8)
+                            .map(|byte| (*byte >> (7 - x % 8)) as u32 & 1)
+                    })
+                    .collect()
+            }
+            Netpbm::P5() | Netpbm::P6() if maxval < 256 => header
+                .data()
+                .iter()
+                .take(count)
+                .map(|byte| *byte as u32)
+                .collect(),
+            Netpbm::P5() | Netpbm::P6() => header
+                .data()
+                .chunks_exact(2)
+                .take(count)
+                .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]) as u32)
+                .collect(),
+        };
+        if samples.len() != count {
+            return Err(String::from("Too few samples in image"));
+        }
+        if let Some(sample) = samples.iter().find(|sample| **sample > maxval) {
+            return Err(format!("Sample {} is above the maximum {}", sample, maxval));
+        }
+
+        let scale = |sample: u32| {
+            if format.is_bitmap() {
+                255 - 255 * sample as i64
+            } else {
+                (sample as f64 * 255.0 / maxval as f64).round() as i64
+            }
+        };
+        let mut out = Self::empty();
+        for (i, pixel) in samples.chunks_exact(format.channels()).enumerate() {
+            let c = match pixel {
+                [v] => [scale(*v), scale(*v), scale(*v), 255],
+                [r, g, b] => [scale(*r), scale(*g), scale(*b), 255],
+                _ => unreachable!(),
+            };
+            out.insert(IqPixel {
+                y: i as u32 / w,
+                x: i as u32 % w,
+                c,
+            });
+        }
+        Ok(out)
+    }
+
+    /// Encodes the pixels in a netpbm format with a maximum value of 255.
+    /// Alpha is dropped, graymaps keep the luma and bitmaps are black where
+    /// the luma is below half.
+    pub fn to_netpbm_bytes(&self, format: Netpbm, overflow: OverflowPolicy) -> Vec<u8> {
+        let img = self.to_image(overflow, (self.max_y + 1, self.max_x + 1));
+        let (w, h) = img.dimensions();
+        let samples: Vec<u8> = img
+            .pixels()
+            .flat_map(|pixel| match format.channels() {
+                3 => pixel.0[..3].to_vec(),
+                _ if format.is_bitmap() => vec![(luma(pixel.0.map(i64::from)) < 127.5) as u8],
+                _ => vec![luma(pixel.0.map(i64::from)).round() as u8],
+            })
+            .collect();
+
+        let mut out = format!("{}\n{} {}\n", format.magic(), w, h).into_bytes();
+        if !format.is_bitmap() {
+            out.extend(b"255\n");
+        }
+        let row_len = w as usize * format.channels();
+        match format {
+            Netpbm::P4() => {
+                for row in samples.chunks(w as usize) {
+                    for bits in row.chunks(8) {
+                        out.push(
+                            bits.iter()
+                                .enumerate()
+                                .fold(0, |byte, (i, bit)| byte | bit << (7 - i)),
+                        );
+                    }
+                }
+            }
+            _ if format.is_plain() => {
+                for row in samples.chunks(row_len) {
+                    let row: Vec<String> = row.iter().map(|sample| sample.to_string()).collect();
+                    out.extend(row.join(" ").as_bytes());
+                    out.push(b'\n');
+                }
+            }
+            _ => out.extend(samples),
+        }
+        out
+    }
+
+    /// Reads a colour (`PF`) or greyscale (`Pf`) PFM image. Samples in `0..1`
+    /// cover the channel range and are kept as is outside of it.
+    pub fn from_pfm_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let mut header = HeaderReader { bytes, pos: 0 };
+        let channels = match header.token()? {
+            "PF" => 3,
+            "Pf" => 1,
+            magic => return Err(format!("Not a PFM image: {:?}", magic)),
+        };
+        let w: u32 = header.number()?;
+        let h: u32 = header.number()?;
+        // A negative scale marks little endian samples.
+        let scale: f32 = header.number()?;
+
+        if w == 0 || h == 0 {
+            return Err(format!(
+                "Image dimensions should be positive, found {}x{}",
+                w, h
+            ));
+        }
+
+        let data = header.data();
+        let row_len = w as usize * channels * 4;
+        if row_len
+            .checked_mul(h as usize)
+            .is_none_or(|len| data.len() < len)
+        {
+            return Err(String::from("Too few samples in image"));
+        }
+        let mut out = Self::empty();
+        // Rows are stored from the bottom of the image up.
+        for (row, bytes) in data.chunks_exact(row_len).take(h as usize).enumerate() {
+            for (x, pixel) in bytes.chunks_exact(channels * 4).enumerate() {
+                let values: Vec<i64> = pixel
+                    .chunks_exact(4)
+                    .map(|sample| {
+                        let sample = [sample[0], sample[1], sample[2], sample[3]];
+                        let value = if scale < 0.0 {
+                            f32::from_le_bytes(sample)
+                        } else {
+                            f32::from_be_bytes(sample)
+                        };
+                        (value as f64 * 255.0).round() as i64
+                    })
+                    .collect();
+                let c = match values[..] {
+                    [v] => [v, v, v, 255],
+                    [r, g, b] => [r, g, b, 255],
+                    _ => unreachable!(),
+                };
+                out.insert(IqPixel {
+                    y: h - 1 - row as u32,
+                    x: x as u32,
+                    c,
+                });
+            }
+        }
+        Ok(out)
+    }
+
+    /// Encodes the pixels as a little endian colour PFM, dropping alpha.
+    pub fn to_pfm_bytes(&self, overflow: OverflowPolicy) -> Vec<u8> {
+        let img = self.to_image(overflow, (self.max_y + 1, self.max_x + 1));
+        let (w, h) = img.dimensions();
+        let mut out = format!("PF\n{} {}\n-1.0\n", w, h).into_bytes();
+        for y in (0..h).rev() {
+            for x in 0..w {
+                for channel in &img.get_pixel(x, y).0[..3] {
+                    out.extend((*channel as f32 / 255.0).to_le_bytes());
+                }
+            }
+        }
+        out
+    }
+
+    /// Reads ASCII art drawn with the characters of `ASCII_RAMP`, one row per
+    /// line. Short lines are padded with white.
+    pub fn from_ascii_art(text: &str) -> Result<Self, String> {
+        let rows: Vec<&str> = text.trim_end_matches('\n').lines().collect();
+        let w = rows
+            .iter()
+            .map(|row| row.chars().count())
+            .max()
+            .unwrap_or(0);
+        let mut out = Self::empty();
+        for (y, row) in rows.iter().enumerate() {
+            let mut chars = row.chars();
+            for x in 0..w {
+                let ch = chars.next().unwrap_or(' ');
+                let i = ASCII_RAMP
+                    .find(ch)
+                    .ok_or_else(|| format!("Unexpected {:?} in ASCII art", ch))?;
+                let v = ascii_level(i);
+                out.insert(IqPixel {
+                    y: y as u32,
+                    x: x as u32,
+                    c: [v, v, v, 255],
+                });
+            }
+        }
+        Ok(out)
+    }
+
+    /// Draws the pixels with the `ASCII_RAMP` character closest to their luma.
+    pub fn to_ascii_art(&self, overflow: OverflowPolicy) -> String {
+        let img = self.to_image(overflow, (self.max_y + 1, self.max_x + 1));
+        let steps = (ASCII_RAMP.len() - 1) as f64;
+        let mut out = String::new();
+        for row in img.rows() {
+            for pixel in row {
+                let i = ((255.0 - luma(pixel.0.map(i64::from))) * steps / 255.0).round() as usize;
+                out.push(ASCII_RAMP.as_bytes()[i] as char);
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Selects the pixels from the lower bounds up to but not including the
+    /// upper bounds. Missing bounds extend to the edge of the context.
     pub fn subcontext(
         &self,
         y_bounds: (Option<u32>, Option<u32>),
         x_bounds: (Option<u32>, Option<u32>),
     ) -> Self {
         let lby = y_bounds.0.unwrap_or(self.min_y);
-        let uby = y_bounds.1.unwrap_or(self.max_y);
+        let uby = y_bounds.1.unwrap_or(self.max_y + 1);
         let lbx = x_bounds.0.unwrap_or(self.min_x);
-        let ubx = x_bounds.1.unwrap_or(self.max_x);
+        let ubx = x_bounds.1.unwrap_or(self.max_x + 1);
 
         let mut subctx = Self::empty();
         for pixel in self.iter() {
-            if lby <= pixel.y && pixel.y <= uby && lbx <= pixel.x && pixel.x <= ubx {
+            if lby <= pixel.y && pixel.y < uby && lbx <= pixel.x && pixel.x < ubx {
                 subctx.insert(pixel.clone())
             }
         }
         subctx
     }
 
+    /// Keeps every `step`th row and column counting from `origin`.
+    pub fn stride(&self, origin: (u32, u32), step: (u32, u32)) -> Self {
+        let mut strided = Self::empty();
+        for pixel in self.iter() {
+            if pixel.y >= origin.0
+                && pixel.x >= origin.1
+                && (pixel.y - origin.0).is_multiple_of(step.0)
+                && (pixel.x - origin.1).is_multiple_of(step.1)
+            {
+                strided.insert(pixel.clone())
+            }
+        }
+        strided
+    }
+
+    /// The number of columns spanned by the context.
     pub fn width(&self) -> u32 {
-        self.max_x - self.min_x
+        if self.pixels.is_empty() {
+            0
+        } else {
+            self.max_x - self.min_x + 1
+        }
     }
 
+    /// The number of rows spanned by the context.
     pub fn height(&self) -> u32 {
-        self.max_y - self.min_y
+        if self.pixels.is_empty() {
+            0
+        } else {
+            self.max_y - self.min_y + 1
+        }
     }
 
     pub fn insert(&mut self, pixel: IqPixel) {
-        self.min_x = min(pixel.x, self.min_x);
-        self.max_x = max(pixel.x, self.max_x);
-        self.min_y = min(pixel.y, self.min_y);
-        self.max_y = max(pixel.y, self.max_y);
+        if self.pixels.is_empty() {
+            (self.min_x, self.max_x) = (pixel.x, pixel.x);
+            (self.min_y, self.max_y) = (pixel.y, pixel.y);
+        } else {
+            self.min_x = min(pixel.x, self.min_x);
+            self.max_x = max(pixel.x, self.max_x);
+            self.min_y = min(pixel.y, self.min_y);
+            self.max_y = max(pixel.y, self.max_y);
+        }
         self.pixels.insert((pixel.y, pixel.x), pixel);
     }
 
@@ -193,7 +1513,7 @@ impl<T> Context<T> {
         self.pixels.len()
     }
 
-    pub fn iter(&self) -> std::collections::hash_map::Values<(u32, u32), IqPixel> {
+    pub fn iter(&self) -> std::collections::hash_map::Values<'_, (u32, u32), IqPixel> {
         self.pixels.values()
     }
 
@@ -207,9 +1527,47 @@ impl<T> Context<T> {
         selected_context
     }
 
+    pub fn exclude<U>(&self, exclusion_ctx: &Context<U>) -> Context<T> {
+        let mut remaining_context = Context::empty();
+        for pixel in self.iter() {
+            if !exclusion_ctx.contains((pixel.y, pixel.x)) {
+                remaining_context.insert(pixel.clone())
+            }
+        }
+        remaining_context
+    }
+
+    pub fn contains(&self, loc: (u32, u32)) -> bool {
+        self.pixels.contains_key(&loc)
+    }
+
+    pub fn get(&self, loc: (u32, u32)) -> Option<&IqPixel> {
+        self.pixels.get(&loc)
+    }
+
+    /// The colour at `loc`, or transparent black where there is no pixel, as
+    /// it is when the image is written.
+    pub fn color_at(&self, loc: (u32, u32)) -> [i64; 4] {
+        self.get(loc).map_or([0, 0, 0, 0], |pixel| pixel.c)
+    }
+
+    /// The height and width of the canvas the image is written on, which
+    /// reaches from the origin to the bottom right pixel.
+    pub fn canvas_size(&self) -> (u32, u32) {
+        if self.pixels.is_empty() {
+            (0, 0)
+        } else {
+            (self.max_y + 1, self.max_x + 1)
+        }
+    }
+
     pub fn center(&self) -> IqPixel {
-        let y = self.min_y + (self.max_y - self.min_y) / 2;
-        let x = self.min_x + (self.max_x - self.min_x) / 2;
+        self.center_with_bounds(SliceBounds::default())
+    }
+
+    pub fn center_with_bounds(&self, bounds: SliceBounds) -> IqPixel {
+        let y = self.min_y + bounds.size(self.height()) / 2;
+        let x = self.min_x + bounds.size(self.width()) / 2;
         if let Some(p) = self.pixels.get(&(y, x)) {
             p.clone()
         } else {
@@ -285,12 +1643,15 @@ impl<T> Context<T> {
         }
     }
 
-    pub fn iter_annotations(&self) -> std::collections::hash_map::Iter<IqPixel, T> {
+    pub fn iter_annotations(&self) -> std::collections::hash_map::Iter<'_, IqPixel, T> {
         self.annotations.iter()
     }
 
-    pub fn first(&self) -> &T {
-        self.iter_annotations().next().unwrap().1
+    /// The annotation of the top left pixel, reading row by row.
+    pub fn first(&self) -> Option<&T> {
+        self.iter_annotations()
+            .min_by_key(|(pixel, _)| (pixel.y, pixel.x))
+            .map(|(_, annotation)| annotation)
     }
 
     pub fn like<U>(ctx: &Context<U>, default: &T) -> Self
@@ -304,3 +1665,167 @@ impl<T> Context<T> {
         annotated_ctx
     }
 }
+
+impl BasicContext {
+    /// Turns the image the way its EXIF orientation (1 to 8) says it should
+    /// be displayed, so the result is upright.
+    pub fn orient(&self, orientation: u16) -> Self {
+        ctx_ops::orient(self, orientation)
+    }
+
+    /// Moves the pixels so the top left of their bounding box is at the
+    /// origin, which crops the image to them when it is written.
+    pub fn cropped(&self) -> Self {
+        let ((min_y, _), (min_x, _)) = (self.y_bounds(), self.x_bounds());
+        Self::from_iter(self.iter(), |pixel| IqPixel {
+            y: pixel.y - min_y,
+            x: pixel.x - min_x,
+            c: pixel.c,
+        })
+    }
+}
+
+/// The frames of an animated image and how they are played.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Animation {
+    pub frames: Vec<BasicContext>,
+    pub delays: Vec<Duration>,
+    /// How many times the animation plays, where 0 repeats forever.
+    pub plays: u32,
+}
+
+const NPY_MAGIC: &[u8] = b"\x93NUMPY";
+
+// Skips a run of GIF data sub-blocks, each prefixed with its length and
+// ended by an empty one, returning the offset after them.
+fn skip_gif_sub_blocks(bytes: &[u8], mut i: usize) -> Option<usize> {
+    loop {
+        let len = *bytes.get(i)? as usize;
+        i += 1 + len;
+        if len == 0 {
+            return Some(i);
+        }
+    }
+}
+
+// Reads the loop count of the NETSCAPE2.0 application extension by walking
+// the blocks of the GIF, which counts repeats after the first play.
+fn gif_loop_count(bytes: &[u8]) -> Option<u16> {
+    let color_table = |packed: u8| {
+        if packed & 0x80 != 0 {
+            3 << ((packed & 0x07) + 1)
+        } else {
+            0
+        }
+    };
+    // The header and logical screen descriptor, then the global colour table.
+    let mut i = 13 + color_table(*bytes.get(10)?);
+    loop {
+        match *bytes.get(i)? {
+            0x21 => {
+                let label = *bytes.get(i + 1)?;
+                let data = bytes.get(i + 2..)?;
+                if label == 0xff && data.starts_with(b"\x0bNETSCAPE2.0") {
+                    if let [3, 1, lo, hi, ..] = data[12..] {
+                        return Some(u16::from_le_bytes([lo, hi]));
+                    }
+                }
+                i = skip_gif_sub_blocks(bytes, i + 2)?;
+            }
+            0x2c => {
+                // The image descriptor, its local colour table, the LZW code
+                // size and then the image data.
+                let table = color_table(*bytes.get(i + 9)?);
+                i = skip_gif_sub_blocks(bytes, i + 10 + table + 1)?;
+            }
+            _ => return None,
+        }
+    }
+}
+
+// Reads the loop count of the ANIM chunk by walking the chunks of the RIFF
+// container, where it follows the background colour.
+fn webp_loop_count(bytes: &[u8]) -> Option<u16> {
+    let mut i = 12;
+    loop {
+        let header = bytes.get(i..i + 8)?;
+        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        if &header[..4] == b"ANIM" {
+            let data = bytes.get(i + 8..i + 8 + len.min(6))?;
+            return match data {
+                [_, _, _, _, lo, hi] => Some(u16::from_le_bytes([*lo, *hi])),
+                _ => None,
+            };
+        }
+        // Chunks are padded to an even length.
+        i = i.checked_add(8 + len + len % 2)?;
+    }
+}
+
+impl Animation {
+    /// Reads every frame of an animated GIF, PNG (APNG) or WebP. Other images
+    /// are read as a single frame.
+    pub fn from_path(path: &str) -> Self {
+        let bytes = fs::read(path).unwrap();
+        let (frames, plays) = match image::guess_format(&bytes).unwrap() {
+            ImageFormat::Gif => {
+                let plays = match gif_loop_count(&bytes) {
+                    Some(0) => 0,
+                    Some(n) => n as u32 + 1,
+                    None => 1,
+                };
+                let decoder = GifDecoder::new(Cursor::new(&bytes)).unwrap();
+                (Some(decoder.into_frames()), plays)
+            }
+            ImageFormat::Png => {
+                let decoder = PngDecoder::new(Cursor::new(&bytes)).unwrap();
+                if decoder.is_apng() {
+                    let reader = png::Decoder::new(Cursor::new(&bytes)).read_info().unwrap();
+                    let plays = reader
+                        .info()
+                        .animation_control()
+                        .map_or(0, |control| control.num_plays);
+                    (Some(decoder.apng().into_frames()), plays)
+                } else {
+                    (None, 1)
+                }
+            }
+            ImageFormat::WebP => {
+                let plays = webp_loop_count(&bytes).map_or(1, |n| n as u32);
+                let decoder = WebPDecoder::new(Cursor::new(&bytes)).unwrap();
+                (Some(decoder.into_frames()), plays)
+            }
+            _ => (None, 1),
+        };
+
+        let mut animation = Self {
+            frames: vec![],
+            delays: vec![],
+            plays,
+        };
+        for frame in frames.into_iter().flatten() {
+            let frame = frame.unwrap();
+            let (numer, denom) = frame.delay().numer_denom_ms();
+            animation.delays.push(Duration::from_secs_f64(
+                numer as f64 / denom as f64 / 1000.0,
+            ));
+            animation.frames.push(BasicContext::from_image(
+                frame.buffer(),
+                (frame.top(), frame.left()),
+            ));
+        }
+        if animation.frames.is_empty() {
+            animation.frames.push(BasicContext::from_path(path));
+            animation.delays.push(Duration::ZERO);
+        }
+        animation
+    }
+
+    pub fn is_animated(&self) -> bool {
+        self.frames.len() > 1
+    }
+
+    pub fn write(&self, path: &str, overflow: OverflowPolicy) -> Vec<String> {
+        BasicContext::write_animation(&self.frames, &self.delays, self.plays, path, overflow)
+    }
+}
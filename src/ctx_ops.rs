@@ -1,133 +1,232 @@
 use crate::context::*;
-use std::vec::Vec;
 
-pub fn center(ctx: &BasicContext) -> AnnotatedPixelContext {
-    AnnotatedPixelContext::like(ctx, &ctx.center())
+pub fn center(ctx: &BasicContext, bounds: SliceBounds) -> AnnotatedPixelContext {
+    AnnotatedPixelContext::like(ctx, &ctx.center_with_bounds(bounds))
 }
 
-pub fn neighbors(arg: &AnnotatedPixelContext, dy: i64, dx: i64) -> AnnotatedPixelContext {
+pub fn neighbors(
+    arg: &AnnotatedPixelContext,
+    dy: &AnnotatedFloatContext,
+    dx: &AnnotatedFloatContext,
+    edge_mode: EdgeMode,
+) -> AnnotatedPixelContext {
     AnnotatedPixelContext::from_iter_with_annotation(arg.iter_annotations(), |(pixel, annot)| {
-        let ny = (annot.y as i64 + dy) as u32;
-        let nx = (annot.x as i64 + dx) as u32;
-        let default = IqPixel {
-            y: ny,
-            x: nx,
-            c: [0, 0, 0, 0],
-        };
-        (
-            pixel.clone(),
-            IqPixel {
+        let dy = dy.get_annotation(pixel).unwrap().round() as i64;
+        let dx = dx.get_annotation(pixel).unwrap().round() as i64;
+        let ny = edge_mode.resolve(annot.y as i64 + dy, arg.y_bounds());
+        let nx = edge_mode.resolve(annot.x as i64 + dx, arg.x_bounds());
+        let neighbor = match (ny, nx) {
+            (Some(ny), Some(nx)) => IqPixel {
                 y: ny,
                 x: nx,
-                c: arg.get_annotation_at_loc((ny, nx)).unwrap_or(&default).c,
+                c: arg
+                    .get_annotation_at_loc((ny, nx))
+                    .map_or([0, 0, 0, 0], |neighbor| neighbor.c),
             },
+            _ => IqPixel {
+                y: pixel.y,
+                x: pixel.x,
+                c: [0, 0, 0, 0],
+            },
+        };
+        (pixel.clone(), neighbor)
+    })
+}
+
+/// Turns an image the way its EXIF orientation (1 to 8) says it should be
+/// displayed, so the result is upright.
+pub fn orient(ctx: &BasicContext, orientation: u16) -> BasicContext {
+    let (h, w) = (ctx.height(), ctx.width());
+    BasicContext::from_iter(ctx.iter(), |pixel| {
+        let (y, x) = (pixel.y, pixel.x);
+        let (y, x) = match orientation {
+            2 => (y, w - 1 - x),
+            3 => (h - 1 - y, w - 1 - x),
+            4 => (h - 1 - y, x),
+            5 => (x, y),
+            6 => (x, h - 1 - y),
+            7 => (w - 1 - x, h - 1 - y),
+            8 => (w - 1 - x, y),
+            _ => (y, x),
+        };
+        IqPixel { y, x, c: pixel.c }
+    })
+}
+
+/// Reads the pixel at each location from an earlier frame, keeping the
+/// current pixel when there is no earlier frame or it doesn't cover the
+/// location.
+pub fn previous_frame(
+    arg: &AnnotatedPixelContext,
+    earlier: Option<&BasicContext>,
+) -> AnnotatedPixelContext {
+    let Some(earlier) = earlier else {
+        return arg.clone();
+    };
+    AnnotatedPixelContext::from_iter_with_annotation(arg.iter_annotations(), |(pixel, annot)| {
+        (
+            pixel.clone(),
+            earlier
+                .get((annot.y, annot.x))
+                .cloned()
+                .unwrap_or_else(|| annot.clone()),
         )
     })
 }
 
-pub fn color_scale(arg: &AnnotatedPixelContext, scale_factor: f64) -> AnnotatedPixelContext {
+pub fn color_scale(
+    arg: &AnnotatedPixelContext,
+    scale_factors: &[AnnotatedFloatContext],
+) -> AnnotatedPixelContext {
     AnnotatedPixelContext::from_iter_with_annotation(arg.iter_annotations(), |(pixel, annot)| {
+        // A single factor scales all colour channels, otherwise each channel
+        // has its own factor and alpha is only scaled when a fourth is given.
+        let scale_channel = |i: usize| {
+            let scale_factor = match scale_factors.len() {
+                1 if i < 3 => scale_factors.first(),
+                1 => None,
+                _ => scale_factors.get(i),
+            };
+            match scale_factor {
+                Some(scale_factor) => {
+                    (annot.c[i] as f64 * scale_factor.get_annotation(pixel).unwrap()) as i64
+                }
+                None => annot.c[i],
+            }
+        };
         (
             pixel.clone(),
             IqPixel {
                 y: pixel.y,
                 x: pixel.x,
                 c: [
-                    (annot.c[0] as f64 * scale_factor) as i64,
-                    (annot.c[1] as f64 * scale_factor) as i64,
-                    (annot.c[2] as f64 * scale_factor) as i64,
-                    annot.c[3],
+                    scale_channel(0),
+                    scale_channel(1),
+                    scale_channel(2),
+                    scale_channel(3),
                 ],
             },
         )
     })
 }
 
-pub fn color_add(args: &Vec<AnnotatedPixelContext>) -> AnnotatedPixelContext {
-    if args.is_empty() {
-        return AnnotatedPixelContext::empty();
-    }
-
-    let red_channels: Vec<AnnotatedFloatContext> = args
-        .iter()
-        .map(|pixel_context| {
-            AnnotatedFloatContext::from_iter_with_annotation(
-                pixel_context.iter_annotations(),
-                |(pixel, annot)| (pixel.clone(), annot.c[0] as f64),
-            )
-        })
-        .collect();
-    let green_channels: Vec<AnnotatedFloatContext> = args
-        .iter()
-        .map(|pixel_context| {
-            AnnotatedFloatContext::from_iter_with_annotation(
-                pixel_context.iter_annotations(),
-                |(pixel, annot)| (pixel.clone(), annot.c[0] as f64),
-            )
-        })
-        .collect();
-    let blue_channels: Vec<AnnotatedFloatContext> = args
-        .iter()
-        .map(|pixel_context| {
-            AnnotatedFloatContext::from_iter_with_annotation(
-                pixel_context.iter_annotations(),
-                |(pixel, annot)| (pixel.clone(), annot.c[0] as f64),
-            )
-        })
-        .collect();
-
-    let mut merged_red_channel: AnnotatedFloatContext = AnnotatedFloatContext::empty();
-    let mut merged_green_channel: AnnotatedFloatContext = AnnotatedFloatContext::empty();
-    let mut merged_blue_channel: AnnotatedFloatContext = AnnotatedFloatContext::empty();
+fn color_combine<F>(
+    args: &[AnnotatedPixelContext],
+    include_alpha: bool,
+    f: F,
+) -> AnnotatedPixelContext
+where
+    F: Fn(i64, i64) -> i64,
+{
+    let (first, rest) = match args.split_first() {
+        Some(split) => split,
+        None => return AnnotatedPixelContext::empty(),
+    };
 
-    red_channels.iter().for_each(|r_channel_ctx| {
-        for (pixel, &value) in r_channel_ctx.iter_annotations() {
-            let loc = (pixel.y, pixel.x);
-            if let Some(annot) = merged_red_channel.get_annotation_at_loc(loc) {
-                merged_red_channel.update_annot_at_loc(loc, annot + value);
-            } else {
-                merged_red_channel.insert_with_annotation(pixel.clone(), value);
+    AnnotatedPixelContext::from_iter_with_annotation(first.iter_annotations(), |(pixel, annot)| {
+        let mut c = annot.c;
+        for other in rest {
+            let other_c = other.get_annotation(pixel).unwrap().c;
+            for i in 0..3 {
+                c[i] = f(c[i], other_c[i]);
             }
-        }
-    });
-    green_channels.iter().for_each(|g_channel_ctx| {
-        for (pixel, &value) in g_channel_ctx.iter_annotations() {
-            let loc = (pixel.y, pixel.x);
-            if let Some(annot) = merged_green_channel.get_annotation_at_loc(loc) {
-                merged_green_channel.update_annot_at_loc(loc, annot + value);
-            } else {
-                merged_green_channel.insert_with_annotation(pixel.clone(), value);
+            if include_alpha {
+                c[3] = f(c[3], other_c[3]);
             }
         }
-    });
-    blue_channels.iter().for_each(|b_channel_ctx| {
-        for (pixel, &value) in b_channel_ctx.iter_annotations() {
-            let loc = (pixel.y, pixel.x);
-            if let Some(annot) = merged_blue_channel.get_annotation_at_loc(loc) {
-                merged_blue_channel.update_annot_at_loc(loc, annot + value);
-            } else {
-                merged_blue_channel.insert_with_annotation(pixel.clone(), value);
-            }
-        }
-    });
+        (
+            pixel.clone(),
+            IqPixel {
+                y: pixel.y,
+                x: pixel.x,
+                c,
+            },
+        )
+    })
+}
 
-    AnnotatedPixelContext::from_iter_with_annotation(args.first().unwrap().iter(), |pixel| {
-        let r = *merged_red_channel
-            .get_annotation_at_loc((pixel.y, pixel.x))
-            .unwrap() as i64;
-        let g = *merged_green_channel
-            .get_annotation_at_loc((pixel.y, pixel.x))
-            .unwrap() as i64;
-        let b = *merged_blue_channel
-            .get_annotation_at_loc((pixel.y, pixel.x))
-            .unwrap() as i64;
+pub fn color_add(args: &[AnnotatedPixelContext], include_alpha: bool) -> AnnotatedPixelContext {
+    color_combine(args, include_alpha, |a, b| a + b)
+}
+
+pub fn color_sub(args: &[AnnotatedPixelContext], include_alpha: bool) -> AnnotatedPixelContext {
+    color_combine(args, include_alpha, |a, b| a - b)
+}
+
+pub fn color_mul(args: &[AnnotatedPixelContext], include_alpha: bool) -> AnnotatedPixelContext {
+    // Channels are treated as fractions of 255 so the product stays in range.
+    color_combine(args, include_alpha, |a, b| a * b / 255)
+}
+
+pub fn color_mix(
+    a: &AnnotatedPixelContext,
+    b: &AnnotatedPixelContext,
+    t: &AnnotatedFloatContext,
+    include_alpha: bool,
+) -> AnnotatedPixelContext {
+    AnnotatedPixelContext::from_iter_with_annotation(a.iter_annotations(), |(pixel, a_annot)| {
+        let b_annot = b.get_annotation(pixel).unwrap();
+        let t = *t.get_annotation(pixel).unwrap();
+        let mix = |i: usize| (a_annot.c[i] as f64 * (1.0 - t) + b_annot.c[i] as f64 * t) as i64;
+        (
+            pixel.clone(),
+            IqPixel {
+                y: pixel.y,
+                x: pixel.x,
+                c: [
+                    mix(0),
+                    mix(1),
+                    mix(2),
+                    if include_alpha { mix(3) } else { a_annot.c[3] },
+                ],
+            },
+        )
+    })
+}
+
+pub fn color_clamp(
+    arg: &AnnotatedPixelContext,
+    lower: &AnnotatedFloatContext,
+    upper: &AnnotatedFloatContext,
+    include_alpha: bool,
+) -> AnnotatedPixelContext {
+    AnnotatedPixelContext::from_iter_with_annotation(arg.iter_annotations(), |(pixel, annot)| {
+        let lower = lower.get_annotation(pixel).unwrap().round() as i64;
+        let upper = upper.get_annotation(pixel).unwrap().round() as i64;
+        let clamp = |c: i64| c.max(lower).min(upper);
+        (
+            pixel.clone(),
+            IqPixel {
+                y: pixel.y,
+                x: pixel.x,
+                c: [
+                    clamp(annot.c[0]),
+                    clamp(annot.c[1]),
+                    clamp(annot.c[2]),
+                    if include_alpha {
+                        clamp(annot.c[3])
+                    } else {
+                        annot.c[3]
+                    },
+                ],
+            },
+        )
+    })
+}
 
+pub fn color_abs(arg: &AnnotatedPixelContext) -> AnnotatedPixelContext {
+    AnnotatedPixelContext::from_iter_with_annotation(arg.iter_annotations(), |(pixel, annot)| {
         (
             pixel.clone(),
             IqPixel {
                 y: pixel.y,
                 x: pixel.x,
-                c: [r, g, b, pixel.c[3]],
+                c: [
+                    annot.c[0].abs(),
+                    annot.c[1].abs(),
+                    annot.c[2].abs(),
+                    annot.c[3],
+                ],
             },
         )
     })
@@ -208,8 +307,12 @@ pub fn color_norm(arg: &AnnotatedPixelContext) -> AnnotatedPixelContext {
     })
 }
 
-pub fn alpha_blend(arg: &AnnotatedPixelContext, blend: f64) -> AnnotatedPixelContext {
+pub fn alpha_blend(
+    arg: &AnnotatedPixelContext,
+    blend: &AnnotatedFloatContext,
+) -> AnnotatedPixelContext {
     AnnotatedPixelContext::from_iter_with_annotation(arg.iter_annotations(), |(pixel, annot)| {
+        let blend = *blend.get_annotation(pixel).unwrap();
         (
             pixel.clone(),
             IqPixel {
@@ -225,3 +328,649 @@ pub fn alpha_blend(arg: &AnnotatedPixelContext, blend: f64) -> AnnotatedPixelCon
         )
     })
 }
+
+pub fn saturate(arg: &AnnotatedPixelContext, overflow: OverflowPolicy) -> AnnotatedPixelContext {
+    let annotations: Vec<(&IqPixel, &IqPixel)> = arg.iter_annotations().collect();
+    let mut colors: Vec<[i64; 4]> = annotations.iter().map(|(_, annot)| annot.c).collect();
+    overflow.apply(&mut colors);
+
+    AnnotatedPixelContext::from_iter_with_annotation(
+        annotations.into_iter().zip(colors),
+        |((pixel, annot), c)| {
+            (
+                pixel.clone(),
+                IqPixel {
+                    y: annot.y,
+                    x: annot.x,
+                    c,
+                },
+            )
+        },
+    )
+}
+
+/// A dense copy of a context's colours used by the windowed filters below.
+struct Grid {
+    bounds: ((u32, u32), (u32, u32)),
+    w: usize,
+    cells: Vec<Option<[f64; 4]>>,
+}
+
+impl Grid {
+    fn from_annotations<T, F>(arg: &Context<T>, f: F) -> Self
+    where
+        F: Fn(&T) -> [f64; 4],
+    {
+        let (y_bounds, x_bounds) = (arg.y_bounds(), arg.x_bounds());
+        let h = (y_bounds.1 - y_bounds.0 + 1) as usize;
+        let w = (x_bounds.1 - x_bounds.0 + 1) as usize;
+        let mut grid = Self {
+            bounds: (y_bounds, x_bounds),
+            w,
+            cells: vec![None; h * w],
+        };
+        for (pixel, annot) in arg.iter_annotations() {
+            let i = grid.index(pixel.y, pixel.x);
+            grid.cells[i] = Some(f(annot));
+        }
+        grid
+    }
+
+    fn from_ctx(arg: &AnnotatedPixelContext) -> Self {
+        Self::from_annotations(arg, |annot| annot.c.map(|c| c as f64))
+    }
+
+    // Mask weights are spread over all four channels so the filters below can
+    // be shared with pixel contexts.
+    fn from_weights(arg: &AnnotatedFloatContext) -> Self {
+        Self::from_annotations(arg, |weight| [*weight; 4])
+    }
+
+    fn index(&self, y: u32, x: u32) -> usize {
+        (y - self.bounds.0 .0) as usize * self.w + (x - self.bounds.1 .0) as usize
+    }
+
+    fn get(&self, y: u32, x: u32) -> Option<[f64; 4]> {
+        self.cells[self.index(y, x)]
+    }
+
+    // Out of bounds reads follow the edge mode, where `Zero` yields transparent
+    // black. Locations missing from a sparse context yield `None`.
+    fn sample(&self, y: i64, x: i64, edge_mode: EdgeMode) -> Option<[f64; 4]> {
+        match (
+            edge_mode.resolve(y, self.bounds.0),
+            edge_mode.resolve(x, self.bounds.1),
+        ) {
+            (Some(y), Some(x)) => self.get(y, x),
+            _ => Some([0.0; 4]),
+        }
+    }
+
+    fn map<F>(&self, f: F) -> Self
+    where
+        F: Fn(u32, u32, [f64; 4]) -> [f64; 4],
+    {
+        let mut out = Self {
+            bounds: self.bounds,
+            w: self.w,
+            cells: self.cells.clone(),
+        };
+        for y in self.bounds.0 .0..=self.bounds.0 .1 {
+            for x in self.bounds.1 .0..=self.bounds.1 .1 {
+                let i = self.index(y, x);
+                out.cells[i] = self.cells[i].map(|c| f(y, x, c));
+            }
+        }
+        out
+    }
+
+    fn convolve_1d(&self, weights: &[f64], vertical: bool, edge_mode: EdgeMode) -> Self {
+        let r = (weights.len() / 2) as i64;
+        self.map(|y, x, _| {
+            let mut total = [0.0; 4];
+            let mut total_weight = 0.0;
+            for (k, weight) in weights.iter().enumerate() {
+                let offset = k as i64 - r;
+                let (sy, sx) = if vertical {
+                    (y as i64 + offset, x as i64)
+                } else {
+                    (y as i64, x as i64 + offset)
+                };
+                if let Some(c) = self.sample(sy, sx, edge_mode) {
+                    for i in 0..4 {
+                        total[i] += c[i] * weight;
+                    }
+                    total_weight += weight;
+                }
+            }
+            total.map(|t| t / total_weight)
+        })
+    }
+
+    fn convolve_separable(&self, weights: &[f64], edge_mode: EdgeMode) -> Self {
+        self.convolve_1d(weights, false, edge_mode)
+            .convolve_1d(weights, true, edge_mode)
+    }
+
+    fn window(&self, y: u32, x: u32, r: i64, edge_mode: EdgeMode) -> Vec<(i64, i64, [f64; 4])> {
+        let mut samples = vec![];
+        for dy in -r..=r {
+            for dx in -r..=r {
+                if let Some(c) = self.sample(y as i64 + dy, x as i64 + dx, edge_mode) {
+                    samples.push((dy, dx, c));
+                }
+            }
+        }
+        samples
+    }
+
+    fn extremum<F>(&self, offsets: &[(i64, i64)], edge_mode: EdgeMode, pick: F) -> Self
+    where
+        F: Fn(f64, f64) -> f64,
+    {
+        self.map(|y, x, center| {
+            offsets
+                .iter()
+                .filter_map(|(dy, dx)| self.sample(y as i64 + dy, x as i64 + dx, edge_mode))
+                .fold(center, |accum, c| {
+                    [
+                        pick(accum[0], c[0]),
+                        pick(accum[1], c[1]),
+                        pick(accum[2], c[2]),
+                        pick(accum[3], c[3]),
+                    ]
+                })
+        })
+    }
+
+    fn to_weights(&self, arg: &AnnotatedFloatContext) -> AnnotatedFloatContext {
+        AnnotatedFloatContext::from_iter_with_annotation(arg.iter_annotations(), |(pixel, _)| {
+            (
+                pixel.clone(),
+                self.get(pixel.y, pixel.x).unwrap()[0].clamp(0.0, 1.0),
+            )
+        })
+    }
+
+    fn to_ctx(&self, arg: &AnnotatedPixelContext) -> AnnotatedPixelContext {
+        AnnotatedPixelContext::from_iter_with_annotation(arg.iter_annotations(), |(pixel, _)| {
+            (
+                pixel.clone(),
+                IqPixel {
+                    y: pixel.y,
+                    x: pixel.x,
+                    c: self
+                        .get(pixel.y, pixel.x)
+                        .unwrap()
+                        .map(|c| c.round() as i64),
+                },
+            )
+        })
+    }
+}
+
+fn morph_extremum<F>(
+    arg: &AnnotatedPixelContext,
+    radius: i64,
+    element: StructuringElement,
+    edge_mode: EdgeMode,
+    pick: F,
+) -> AnnotatedPixelContext
+where
+    F: Fn(f64, f64) -> f64,
+{
+    if arg.count() == 0 {
+        return AnnotatedPixelContext::empty();
+    }
+
+    Grid::from_ctx(arg)
+        .extremum(&element.offsets(radius), edge_mode, pick)
+        .to_ctx(arg)
+}
+
+pub fn erode(
+    arg: &AnnotatedPixelContext,
+    radius: i64,
+    element: StructuringElement,
+    edge_mode: EdgeMode,
+) -> AnnotatedPixelContext {
+    morph_extremum(arg, radius, element, edge_mode, f64::min)
+}
+
+pub fn dilate(
+    arg: &AnnotatedPixelContext,
+    radius: i64,
+    element: StructuringElement,
+    edge_mode: EdgeMode,
+) -> AnnotatedPixelContext {
+    morph_extremum(arg, radius, element, edge_mode, f64::max)
+}
+
+fn offset_loc(pixel: &IqPixel, (dy, dx): (i64, i64)) -> Option<(u32, u32)> {
+    let (y, x) = (pixel.y as i64 + dy, pixel.x as i64 + dx);
+    if y < 0 || x < 0 {
+        None
+    } else {
+        Some((y as u32, x as u32))
+    }
+}
+
+/// Shrinks a mask. Locations outside of `domain` don't count against a pixel
+/// so masks touching the image border aren't eaten away.
+pub fn erode_mask(
+    domain: &BasicContext,
+    mask: &BasicContext,
+    radius: i64,
+    element: StructuringElement,
+) -> BasicContext {
+    let offsets = element.offsets(radius);
+    BasicContext::from_iter(
+        mask.iter().filter(|pixel| {
+            offsets
+                .iter()
+                .all(|offset| match offset_loc(pixel, *offset) {
+                    Some(loc) => !domain.contains(loc) || mask.contains(loc),
+                    None => true,
+                })
+        }),
+        |pixel| pixel.clone(),
+    )
+}
+
+pub fn dilate_mask(
+    domain: &BasicContext,
+    mask: &BasicContext,
+    radius: i64,
+    element: StructuringElement,
+) -> BasicContext {
+    let offsets = element.offsets(radius);
+    BasicContext::from_iter(
+        domain.iter().filter(|pixel| {
+            offsets
+                .iter()
+                .any(|offset| match offset_loc(pixel, *offset) {
+                    Some(loc) => mask.contains(loc),
+                    None => false,
+                })
+        }),
+        |pixel| pixel.clone(),
+    )
+}
+
+/// Masks built from conditions have a weight of 1 for selected pixels and 0
+/// elsewhere across the whole image. Feathering produces weights in between.
+pub fn mask_weights(domain: &BasicContext, mask: &BasicContext) -> AnnotatedFloatContext {
+    AnnotatedFloatContext::from_iter_with_annotation(domain.iter(), |pixel| {
+        (
+            pixel.clone(),
+            if mask.contains((pixel.y, pixel.x)) {
+                1.0
+            } else {
+                0.0
+            },
+        )
+    })
+}
+
+/// The coverage of each pixel of `domain` by a shape. Pixels outside of the
+/// shape are left out.
+pub fn shape_weights(
+    domain: &BasicContext,
+    shape: &Shape,
+    edge: ShapeEdge,
+) -> AnnotatedFloatContext {
+    let mut weights = AnnotatedFloatContext::empty();
+    for pixel in domain.iter() {
+        let coverage = shape.coverage(pixel.y, pixel.x, edge);
+        if coverage > 0.0 {
+            weights.insert_with_annotation(pixel.clone(), coverage);
+        }
+    }
+    weights
+}
+
+// Masks are grown, shrunk and feathered with clamped edges so the image border
+// neither adds to nor eats away at a mask.
+pub fn grow_mask(
+    mask: &AnnotatedFloatContext,
+    radius: i64,
+    element: StructuringElement,
+) -> AnnotatedFloatContext {
+    if mask.count() == 0 {
+        return AnnotatedFloatContext::empty();
+    }
+
+    Grid::from_weights(mask)
+        .extremum(&element.offsets(radius), EdgeMode::Clamp(), f64::max)
+        .to_weights(mask)
+}
+
+pub fn shrink_mask(
+    mask: &AnnotatedFloatContext,
+    radius: i64,
+    element: StructuringElement,
+) -> AnnotatedFloatContext {
+    if mask.count() == 0 {
+        return AnnotatedFloatContext::empty();
+    }
+
+    Grid::from_weights(mask)
+        .extremum(&element.offsets(radius), EdgeMode::Clamp(), f64::min)
+        .to_weights(mask)
+}
+
+pub fn feather_mask(mask: &AnnotatedFloatContext, sigma: f64) -> AnnotatedFloatContext {
+    if mask.count() == 0 {
+        return AnnotatedFloatContext::empty();
+    }
+
+    Grid::from_weights(mask)
+        .convolve_separable(&gaussian_weights(sigma), EdgeMode::Clamp())
+        .to_weights(mask)
+}
+
+/// Fades pixels out by the weight of the mask they were selected with, so a
+/// feathered selection blends into the layers beneath it.
+pub fn fade_by_mask(arg: &BasicContext, mask: &AnnotatedFloatContext) -> BasicContext {
+    BasicContext::from_iter(arg.iter(), |pixel| {
+        let weight = mask
+            .get_annotation_at_loc((pixel.y, pixel.x))
+            .unwrap_or(&1.0);
+        let mut c = pixel.c;
+        c[3] = (c[3] as f64 * weight).round() as i64;
+        IqPixel {
+            y: pixel.y,
+            x: pixel.x,
+            c,
+        }
+    })
+}
+
+fn gaussian_weights(sigma: f64) -> Vec<f64> {
+    if sigma <= 0.0 {
+        return vec![1.0];
+    }
+    let r = (3.0 * sigma).ceil() as i64;
+    (-r..=r)
+        .map(|i| (-((i * i) as f64) / (2.0 * sigma * sigma)).exp())
+        .collect()
+}
+
+pub fn box_blur(arg: &AnnotatedPixelContext, r: i64, edge_mode: EdgeMode) -> AnnotatedPixelContext {
+    if arg.count() == 0 {
+        return AnnotatedPixelContext::empty();
+    }
+
+    let weights = vec![1.0; (2 * r.max(0) + 1) as usize];
+    Grid::from_ctx(arg)
+        .convolve_separable(&weights, edge_mode)
+        .to_ctx(arg)
+}
+
+pub fn gaussian_blur(
+    arg: &AnnotatedPixelContext,
+    sigma: f64,
+    edge_mode: EdgeMode,
+) -> AnnotatedPixelContext {
+    if arg.count() == 0 {
+        return AnnotatedPixelContext::empty();
+    }
+
+    Grid::from_ctx(arg)
+        .convolve_separable(&gaussian_weights(sigma), edge_mode)
+        .to_ctx(arg)
+}
+
+pub fn unsharp_mask(
+    arg: &AnnotatedPixelContext,
+    sigma: f64,
+    amount: f64,
+    edge_mode: EdgeMode,
+) -> AnnotatedPixelContext {
+    if arg.count() == 0 {
+        return AnnotatedPixelContext::empty();
+    }
+
+    let grid = Grid::from_ctx(arg);
+    let blurred = grid.convolve_separable(&gaussian_weights(sigma), edge_mode);
+    grid.map(|y, x, c| {
+        let b = blurred.get(y, x).unwrap();
+        [
+            c[0] + amount * (c[0] - b[0]),
+            c[1] + amount * (c[1] - b[1]),
+            c[2] + amount * (c[2] - b[2]),
+            c[3],
+        ]
+    })
+    .to_ctx(arg)
+}
+
+pub fn median(arg: &AnnotatedPixelContext, r: i64, edge_mode: EdgeMode) -> AnnotatedPixelContext {
+    if arg.count() == 0 {
+        return AnnotatedPixelContext::empty();
+    }
+
+    let grid = Grid::from_ctx(arg);
+    grid.map(|y, x, _| {
+        let samples = grid.window(y, x, r.max(0), edge_mode);
+        let median_channel = |i: usize| {
+            let mut values: Vec<f64> = samples.iter().map(|(_, _, c)| c[i]).collect();
+            values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            values[values.len() / 2]
+        };
+        [
+            median_channel(0),
+            median_channel(1),
+            median_channel(2),
+            median_channel(3),
+        ]
+    })
+    .to_ctx(arg)
+}
+
+pub fn bilateral(
+    arg: &AnnotatedPixelContext,
+    sigma_s: f64,
+    sigma_r: f64,
+    edge_mode: EdgeMode,
+) -> AnnotatedPixelContext {
+    if arg.count() == 0 || sigma_s <= 0.0 || sigma_r <= 0.0 {
+        return arg.clone();
+    }
+
+    let grid = Grid::from_ctx(arg);
+    let r = (2.0 * sigma_s).ceil() as i64;
+    grid.map(|y, x, center| {
+        let mut total = [0.0; 4];
+        let mut total_weight = 0.0;
+        for (dy, dx, c) in grid.window(y, x, r, edge_mode) {
+            let spatial = ((dy * dy + dx * dx) as f64) / (2.0 * sigma_s * sigma_s);
+            let range =
+                (0..3).map(|i| (c[i] - center[i]).powi(2)).sum::<f64>() / (2.0 * sigma_r * sigma_r);
+            let weight = (-spatial - range).exp();
+            for i in 0..4 {
+                total[i] += c[i] * weight;
+            }
+            total_weight += weight;
+        }
+        total.map(|t| t / total_weight)
+    })
+    .to_ctx(arg)
+}
+
+fn rgb(c: [f64; 4]) -> [f64; 3] {
+    [c[0], c[1], c[2]]
+}
+
+fn distance_sq(a: [f64; 3], b: [f64; 3]) -> f64 {
+    (0..3).map(|i| (a[i] - b[i]).powi(2)).sum()
+}
+
+fn nearest_index(targets: &[[f64; 3]], c: [f64; 3]) -> usize {
+    (0..targets.len())
+        .min_by(|&i, &j| {
+            distance_sq(targets[i], c)
+                .partial_cmp(&distance_sq(targets[j], c))
+                .unwrap()
+        })
+        .unwrap()
+}
+
+const KMEANS_ROUNDS: usize = 4;
+
+/// Picks up to `n` colours representing `arg` by median cut, refined with a
+/// few rounds of k-means.
+pub fn median_cut(arg: &AnnotatedPixelContext, n: usize) -> Vec<[f64; 3]> {
+    // Contexts iterate in no particular order, so sort to keep the palette
+    // reproducible.
+    let mut colors: Vec<[f64; 3]> = arg
+        .iter_annotations()
+        .map(|(_, annot)| rgb(annot.c.map(|c| c as f64)))
+        .collect();
+    colors.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    if colors.is_empty() || n == 0 {
+        return vec![];
+    }
+
+    let widest_channel = |colors: &[[f64; 3]]| {
+        (0..3)
+            .map(|i| {
+                let (lo, hi) = colors.iter().fold((f64::MAX, f64::MIN), |(lo, hi), c| {
+                    (lo.min(c[i]), hi.max(c[i]))
+                });
+                (i, hi - lo)
+            })
+            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+            .unwrap()
+    };
+    let mut boxes = vec![colors.clone()];
+    while boxes.len() < n {
+        let (index, (channel, range)) = boxes
+            .iter()
+            .map(|b| widest_channel(b))
+            .enumerate()
+            .max_by(|a, b| a.1 .1.partial_cmp(&b.1 .1).unwrap())
+            .unwrap();
+        if range <= 0.0 {
+            break;
+        }
+        let mut lower = boxes.remove(index);
+        lower.sort_by(|a, b| (a[channel], a).partial_cmp(&(b[channel], b)).unwrap());
+        let upper = lower.split_off(lower.len() / 2);
+        boxes.push(lower);
+        boxes.push(upper);
+    }
+
+    let mut palette: Vec<[f64; 3]> = boxes
+        .iter()
+        .map(|b| {
+            let total = b
+                .iter()
+                .fold([0.0; 3], |t, c| [t[0] + c[0], t[1] + c[1], t[2] + c[2]]);
+            total.map(|t| t / b.len() as f64)
+        })
+        .collect();
+    for _ in 0..KMEANS_ROUNDS {
+        let mut totals = vec![([0.0; 3], 0); palette.len()];
+        for c in &colors {
+            let (total, count) = &mut totals[nearest_index(&palette, *c)];
+            for i in 0..3 {
+                total[i] += c[i];
+            }
+            *count += 1;
+        }
+        for (entry, (total, count)) in palette.iter_mut().zip(totals) {
+            if count > 0 {
+                *entry = total.map(|t| t / count as f64);
+            }
+        }
+    }
+    palette.iter().map(|c| c.map(f64::round)).collect()
+}
+
+// Offsets and weights receiving the quantization error of a pixel.
+fn diffusion_kernel(dither: Dither) -> &'static [(i64, i64, f64)] {
+    match dither {
+        Dither::FloydSteinberg() => &[
+            (0, 1, 7.0 / 16.0),
+            (1, -1, 3.0 / 16.0),
+            (1, 0, 5.0 / 16.0),
+            (1, 1, 1.0 / 16.0),
+        ],
+        Dither::Atkinson() => &[
+            (0, 1, 1.0 / 8.0),
+            (0, 2, 1.0 / 8.0),
+            (1, -1, 1.0 / 8.0),
+            (1, 0, 1.0 / 8.0),
+            (1, 1, 1.0 / 8.0),
+            (2, 0, 1.0 / 8.0),
+        ],
+        Dither::None() | Dither::Bayer() => &[],
+    }
+}
+
+const BAYER_4X4: [[f64; 4]; 4] = [
+    [0.0, 8.0, 2.0, 10.0],
+    [12.0, 4.0, 14.0, 6.0],
+    [3.0, 11.0, 1.0, 9.0],
+    [15.0, 7.0, 13.0, 5.0],
+];
+
+/// Replaces each colour with the nearest colour of `palette`, spreading the
+/// difference to later pixels or over a threshold pattern when dithering.
+/// Alpha is left untouched.
+pub fn apply_palette(
+    arg: &AnnotatedPixelContext,
+    palette: &[[f64; 3]],
+    options: PaletteOptions,
+) -> AnnotatedPixelContext {
+    if arg.count() == 0 || palette.is_empty() {
+        return arg.clone();
+    }
+
+    let targets: Vec<[f64; 3]> = palette.iter().map(|c| options.space.convert(*c)).collect();
+    // Ordered dithering offsets colours by about the gap between palette
+    // entries, assuming they are spread evenly over the colour cube.
+    let spread = 255.0 / ((palette.len() as f64).cbrt() - 1.0).max(1.0);
+    let kernel = diffusion_kernel(options.dither);
+
+    let mut grid = Grid::from_ctx(arg);
+    let ((min_y, max_y), (min_x, max_x)) = grid.bounds;
+    for y in min_y..=max_y {
+        for x in min_x..=max_x {
+            let i = grid.index(y, x);
+            let Some(c) = grid.cells[i] else {
+                continue;
+            };
+            let mut target = rgb(c);
+            if options.dither == Dither::Bayer() {
+                let threshold = (BAYER_4X4[y as usize % 4][x as usize % 4] + 0.5) / 16.0 - 0.5;
+                target = target.map(|t| t + threshold * spread);
+            }
+            let chosen = palette[nearest_index(&targets, options.space.convert(target))];
+            grid.cells[i] = Some([chosen[0], chosen[1], chosen[2], c[3]]);
+
+            for (dy, dx, weight) in kernel {
+                let (ny, nx) = (y as i64 + dy, x as i64 + dx);
+                if ny > max_y as i64 || nx < min_x as i64 || nx > max_x as i64 {
+                    continue;
+                }
+                let j = grid.index(ny as u32, nx as u32);
+                if let Some(neighbor) = &mut grid.cells[j] {
+                    for k in 0..3 {
+                        neighbor[k] += (c[k] - chosen[k]) * weight;
+                    }
+                }
+            }
+        }
+    }
+    grid.to_ctx(arg)
+}
+
+pub fn quantize(
+    arg: &AnnotatedPixelContext,
+    n: usize,
+    options: PaletteOptions,
+) -> AnnotatedPixelContext {
+    apply_palette(arg, &median_cut(arg, n), options)
+}
@@ -0,0 +1,157 @@
+use crate::context::{luma, BasicContext, IqPixel};
+
+/// The largest and mean absolute difference of one channel.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChannelError {
+    pub max: i64,
+    pub mean: f64,
+}
+
+/// How far apart two images are. `psnr` is in decibels over the colour
+/// channels and infinite for identical images, and `ssim` is the mean
+/// structural similarity of their luma, where 1 means identical.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comparison {
+    pub channels: [ChannelError; 4],
+    pub psnr: f64,
+    pub ssim: f64,
+}
+
+impl Comparison {
+    /// The largest mean absolute error of any channel.
+    pub fn mean_error(&self) -> f64 {
+        self.channels
+            .iter()
+            .map(|channel| channel.mean)
+            .fold(0.0, f64::max)
+    }
+}
+
+const SSIM_WINDOW: u32 = 8;
+const SSIM_STEP: u32 = 4;
+
+fn locations((h, w): (u32, u32)) -> impl Iterator<Item = (u32, u32)> {
+    (0..h).flat_map(move |y| (0..w).map(move |x| (y, x)))
+}
+
+// Mean SSIM over windows stepping across the image. Images smaller than a
+// window are compared as a single window, and the last window of each axis is
+// aligned to its end so every pixel is covered.
+fn ssim(a: &[f64], b: &[f64], (h, w): (u32, u32)) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+    let (wh, ww) = (SSIM_WINDOW.min(h), SSIM_WINDOW.min(w));
+    let starts = |len: u32, window: u32| {
+        let last = len - window;
+        let mut starts: Vec<u32> = (0..=last).step_by(SSIM_STEP as usize).collect();
+        if starts.last() != Some(&last) {
+            starts.push(last);
+        }
+        starts
+    };
+
+    let mut total = 0.0;
+    let mut windows = 0;
+    for top in starts(h, wh) {
+        for left in starts(w, ww) {
+            let indices: Vec<usize> = locations((wh, ww))
+                .map(|(y, x)| ((top + y) * w + left + x) as usize)
+                .collect();
+            let n = indices.len() as f64;
+            let mean = |v: &[f64]| indices.iter().map(|i| v[*i]).sum::<f64>() / n;
+            let (ma, mb) = (mean(a), mean(b));
+            let (mut va, mut vb, mut cov) = (0.0, 0.0, 0.0);
+            for i in &indices {
+                let (da, db) = (a[*i] - ma, b[*i] - mb);
+                va += da * da;
+                vb += db * db;
+                cov += da * db;
+            }
+            let (va, vb, cov) = (va / n, vb / n, cov / n);
+            total += ((2.0 * ma * mb + C1) * (2.0 * cov + C2))
+                / ((ma * ma + mb * mb + C1) * (va + vb + C2));
+            windows += 1;
+        }
+    }
+    total / windows as f64
+}
+
+/// Compares two images of the same size.
+pub fn compare(a: &BasicContext, b: &BasicContext) -> Result<Comparison, String> {
+    let (sa, sb) = (a.canvas_size(), b.canvas_size());
+    if sa != sb {
+        return Err(format!(
+            "Images should be the same size, found {}x{} and {}x{}",
+            sa.0, sa.1, sb.0, sb.1
+        ));
+    }
+    let n = (sa.0 * sa.1).max(1) as f64;
+
+    let mut channels = [ChannelError::default(); 4];
+    let mut squared = 0.0;
+    let (mut luma_a, mut luma_b) = (vec![], vec![]);
+    for loc in locations(sa) {
+        let (ca, cb) = (a.color_at(loc), b.color_at(loc));
+        for (i, channel) in channels.iter_mut().enumerate() {
+            let d = (ca[i] - cb[i]).abs();
+            channel.max = channel.max.max(d);
+            channel.mean += d as f64 / n;
+            if i < 3 {
+                squared += (d * d) as f64;
+            }
+        }
+        luma_a.push(luma(ca));
+        luma_b.push(luma(cb));
+    }
+
+    let mse = squared / (3.0 * n);
+    let psnr = 10.0 * (255.0 * 255.0 / mse).log10();
+    let ssim = if luma_a.is_empty() {
+        1.0
+    } else {
+        ssim(&luma_a, &luma_b, sa)
+    };
+    Ok(Comparison {
+        channels,
+        psnr,
+        ssim,
+    })
+}
+
+// Black through red and yellow to white.
+const HEAT: [[f64; 3]; 4] = [
+    [0.0, 0.0, 0.0],
+    [255.0, 0.0, 0.0],
+    [255.0, 255.0, 0.0],
+    [255.0, 255.0, 255.0],
+];
+
+/// Colours each pixel by its largest channel difference, scaled so the
+/// largest difference in the image is white.
+pub fn heatmap(a: &BasicContext, b: &BasicContext) -> BasicContext {
+    let (sa, sb) = (a.canvas_size(), b.canvas_size());
+    let canvas = (sa.0.max(sb.0), sa.1.max(sb.1));
+    let differences: Vec<i64> = locations(canvas)
+        .map(|loc| {
+            let (ca, cb) = (a.color_at(loc), b.color_at(loc));
+            (0..4).map(|i| (ca[i] - cb[i]).abs()).max().unwrap()
+        })
+        .collect();
+    let largest = differences.iter().copied().max().unwrap_or(0).max(1) as f64;
+
+    let blank = BasicContext::blank(canvas.0, canvas.1);
+    BasicContext::from_iter(blank.iter(), |pixel| {
+        let d = differences[(pixel.y * canvas.1 + pixel.x) as usize] as f64;
+        let t = d / largest * (HEAT.len() - 1) as f64;
+        let i = (t.floor() as usize).min(HEAT.len() - 2);
+        let f = t - i as f64;
+        let c = |channel: usize| {
+            (HEAT[i][channel] + (HEAT[i + 1][channel] - HEAT[i][channel]) * f).round() as i64
+        };
+        IqPixel {
+            y: pixel.y,
+            x: pixel.x,
+            c: [c(0), c(1), c(2), 255],
+        }
+    })
+}
@@ -1,131 +1,358 @@
 use crate::ast::*;
-use crate::attrs;
-use crate::context::{AnnotatedFloatContext, AnnotatedPixelContext, BasicContext, IqPixel};
+use crate::attrs::{self, Region};
+use crate::context::{
+    AnnotatedFloatContext, AnnotatedPixelContext, BasicContext, EdgeMode, IqPixel, OverflowPolicy,
+    Shape, ShapeEdge, SliceBounds,
+};
 use crate::ctx_ops;
 use crate::float_ops;
+use crate::metadata::Metadata;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// The frame being evaluated when a script is animated. Scripts which aren't
+/// animated are a single frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameTime {
+    pub frame: u32,
+    pub frames: u32,
+}
+
+impl Default for FrameTime {
+    fn default() -> Self {
+        Self {
+            frame: 0,
+            frames: 1,
+        }
+    }
+}
+
+impl FrameTime {
+    /// How far through the animation the frame is, from 0 up to but not
+    /// including 1 so that animations loop smoothly.
+    pub fn t(&self) -> f64 {
+        self.frame as f64 / self.frames.max(1) as f64
+    }
+}
+
+/// Settings which apply to every node of a single evaluation.
+#[derive(Debug, Clone, Default)]
+pub struct EvalEnv {
+    pub overflow: OverflowPolicy,
+    pub edge_mode: EdgeMode,
+    pub bounds: SliceBounds,
+    pub masks: Rc<HashMap<String, AnnotatedFloatContext>>,
+    /// The pixels picked by the selector of the expression being evaluated,
+    /// or `None` outside of one.
+    pub region: Option<Region>,
+    pub seed: u64,
+    pub time: FrameTime,
+    /// Every input frame when processing an animation, read by `prev`.
+    pub inputs: Rc<Vec<BasicContext>>,
+    pub metadata: Rc<Metadata>,
+}
+
+impl EvalEnv {
+    fn mask(&self, name: &str) -> &AnnotatedFloatContext {
+        self.masks
+            .get(name)
+            .unwrap_or_else(|| panic!("Unknown mask: {:}", name))
+    }
+
+    // Earlier frames before the first are the first frame.
+    fn earlier_input(&self, n: u32) -> Option<&BasicContext> {
+        let i = self.time.frame.saturating_sub(n) as usize;
+        self.inputs.get(i.min(self.inputs.len().saturating_sub(1)))
+    }
+
+    // Random values change from frame to frame, like film grain.
+    fn rand_seed(&self) -> u64 {
+        self.seed ^ (self.time.frame as u64).rotate_left(32)
+    }
+}
 
 pub trait Evalulate<T> {
-    fn eval(&self, image_ctx: &BasicContext) -> T;
+    fn eval(&self, image_ctx: &BasicContext, env: &EvalEnv) -> T;
+}
+
+/// The image drawn by a script's expressions along with the value of each of
+/// its scalar statements.
+impl Evalulate<(BasicContext, Vec<f64>)> for IqAstRootNode {
+    fn eval(&self, image_ctx: &BasicContext, env: &EvalEnv) -> (BasicContext, Vec<f64>) {
+        // Masks are evaluated against the input image and are visible to every
+        // statement that follows their definition.
+        let mut env = env.clone();
+        let mut layers = vec![];
+        let mut scalars = vec![];
+        for statement in &self.statements {
+            match statement {
+                StatementNode::Expr(expr) => layers.push(expr.eval(image_ctx, &env)),
+                StatementNode::MaskDef(mask_def) => {
+                    let mask = mask_def.mask_expr.eval(image_ctx, &env);
+                    Rc::make_mut(&mut env.masks).insert(mask_def.name.clone(), mask);
+                }
+                StatementNode::Scalar(scalar) => scalars.push(eval_single(scalar, image_ctx, &env)),
+            }
+        }
+
+        (BasicContext::alpha_composite(layers, env.overflow), scalars)
+    }
 }
 
 impl Evalulate<BasicContext> for IqAstRootNode {
-    fn eval(&self, image_ctx: &BasicContext) -> BasicContext {
-        BasicContext::alpha_composite(
-            (*self.exprs)
-                .iter()
-                .map(|expr| expr.eval(image_ctx))
-                .collect(),
-        )
+    fn eval(&self, image_ctx: &BasicContext, env: &EvalEnv) -> BasicContext {
+        let (image, _): (BasicContext, Vec<f64>) = self.eval(image_ctx, env);
+        image
+    }
+}
+
+// Scalar statements print one number, so per pixel values have to be reduced
+// with an aggregate like `mean` first, which the check pass makes sure of.
+fn eval_single(expr: &ScalarExprNode, image_ctx: &BasicContext, env: &EvalEnv) -> f64 {
+    let values = expr.eval(image_ctx, env);
+    let mut annotations = values.iter_annotations().map(|(_, value)| *value);
+    let first = annotations.next().unwrap_or(f64::NAN);
+    if annotations.any(|value| value != first && !(value.is_nan() && first.is_nan())) {
+        unreachable!("Scalar statement with per pixel values passed the check")
     }
+    first
 }
 
 impl Evalulate<BasicContext> for ExprNode {
-    fn eval(&self, image_ctx: &BasicContext) -> BasicContext {
-        let mut selected_ctx = match &self.selector_ctx {
-            None => image_ctx.clone(),
-            Some(selector_ctx) => selector_ctx.eval(image_ctx),
+    fn eval(&self, image_ctx: &BasicContext, env: &EvalEnv) -> BasicContext {
+        let (mut selected_ctx, weights) = match &self.selector_ctx {
+            None => (image_ctx.clone(), None),
+            Some(selector_ctx) => selector_ctx.select(image_ctx, env),
         };
 
+        // Match arms only see the pixels they matched, so the selected region
+        // is kept for relative attributes. Expressions without a selector keep
+        // the region of the expression they are nested in.
+        let env = EvalEnv {
+            region: match (&self.selector_ctx, env.region) {
+                (None, Some(region)) => Some(region),
+                _ => Some(Region::of(&selected_ctx)),
+            },
+            ..env.clone()
+        };
         for op in &self.op_nodes {
-            selected_ctx = op.eval(&selected_ctx);
+            selected_ctx = op.eval(&selected_ctx, &env);
         }
 
-        selected_ctx
+        match weights {
+            Some(weights) => ctx_ops::fade_by_mask(&selected_ctx, &weights),
+            None => selected_ctx,
+        }
     }
 }
 
 impl SliceRangeNode {
-    fn default_x(image_ctx: &BasicContext) -> Self {
-        Self::with_bounds(0, image_ctx.width().into())
-    }
-
-    fn default_y(image_ctx: &BasicContext) -> Self {
-        Self::with_bounds(0, image_ctx.height().into())
+    fn eval_bounds(
+        &self,
+        axis: (u32, u32),
+        image_ctx: &BasicContext,
+        env: &EvalEnv,
+    ) -> (Option<u32>, Option<u32>) {
+        (
+            self.lower_bound
+                .as_ref()
+                .map(|bound| bound.resolve(axis, image_ctx, env)),
+            self.upper_bound.as_ref().map(|bound| {
+                env.bounds
+                    .exclusive_upper(bound.resolve(axis, image_ctx, env))
+            }),
+        )
     }
 
-    fn with_bounds(lower: i64, upper: i64) -> Self {
-        SliceRangeNode {
-            lower_bound: Some(ScalarExprNode::Scalar(ScalarNode::Integer(lower))),
-            upper_bound: Some(ScalarExprNode::Scalar(ScalarNode::Integer(upper))),
+    // A step below 1 which wasn't caught as a constant by the checker
+    // selects nothing.
+    fn eval_step(&self, image_ctx: &BasicContext, env: &EvalEnv) -> Option<u32> {
+        match &self.step {
+            None => Some(1),
+            Some(step) => {
+                let step = eval_uniform(step, image_ctx, env).round();
+                (step >= 1.0).then_some(step as u32)
+            }
         }
     }
 }
 
-impl Evalulate<BasicContext> for SelectorCtxNode {
-    fn eval(&self, image_ctx: &BasicContext) -> BasicContext {
-        let y_slice_range = match &self.y_slice_range {
-            None => Box::new(SliceRangeNode::default_y(image_ctx)),
-            Some(y_slice_range) => y_slice_range.clone(),
+impl SliceBoundNode {
+    // Like python, bounds count from the start of the axis, or back from its
+    // end when negative. Percentages are of the length of the axis.
+    fn resolve(&self, (lower, upper): (u32, u32), image_ctx: &BasicContext, env: &EvalEnv) -> u32 {
+        let (start, end) = (lower as f64, upper as f64 + 1.0);
+        let (value, offset) = match self {
+            Self::Index(index) => {
+                let index = eval_uniform(index, image_ctx, env);
+                (index, index)
+            }
+            Self::Percent(percent) => {
+                let percent = eval_uniform(percent, image_ctx, env);
+                (percent, (end - start) * percent / 100.0)
+            }
         };
+        let base = if value < 0.0 { end } else { start };
+        (base + offset).round().max(start) as u32
+    }
+}
 
-        let x_slice_range = match &self.x_slice_range {
-            None => Box::new(SliceRangeNode::default_x(image_ctx)),
-            Some(x_slice_range) => x_slice_range.clone(),
+impl SelectorCtxNode {
+    // Selects the pixels of the context along with the weights they should be
+    // faded by, for selectors which can partially cover a pixel.
+    fn select(
+        &self,
+        image_ctx: &BasicContext,
+        env: &EvalEnv,
+    ) -> (BasicContext, Option<AnnotatedFloatContext>) {
+        let weights = match self {
+            Self::Slice(slice_selector) => return (slice_selector.eval(image_ctx, env), None),
+            Self::Shape(shape, edge, _) => {
+                ctx_ops::shape_weights(image_ctx, &shape.eval(image_ctx, env), *edge)
+            }
+            Self::Mask(name, _) => env.mask(name).clone(),
         };
+        let selected_ctx = image_ctx.select(BasicContext::from_iter(
+            weights
+                .iter_annotations()
+                .filter(|(_, weight)| **weight > 0.0)
+                .map(|(pixel, _)| pixel),
+            |pixel| pixel.clone(),
+        ));
 
-        let y_bounds = y_slice_range.eval(image_ctx);
-        let x_bounds = x_slice_range.eval(image_ctx);
+        match self {
+            Self::Shape(_, ShapeEdge::Hard(), _) => (selected_ctx, None),
+            _ => (selected_ctx, Some(weights)),
+        }
+    }
+}
 
-        image_ctx.subcontext(y_bounds, x_bounds)
+impl Evalulate<BasicContext> for SelectorCtxNode {
+    fn eval(&self, image_ctx: &BasicContext, env: &EvalEnv) -> BasicContext {
+        self.select(image_ctx, env).0
     }
 }
 
-impl Evalulate<(Option<u32>, Option<u32>)> for SliceRangeNode {
-    fn eval(&self, image_ctx: &BasicContext) -> (Option<u32>, Option<u32>) {
-        let lower_bound = match &self.lower_bound {
-            None => None,
-            Some(lower_bound) => {
-                let floating_lower_bound: f64 = *lower_bound.eval(image_ctx).first();
-                Some(floating_lower_bound.round() as u32)
+impl Evalulate<Shape> for ShapeNode {
+    fn eval(&self, image_ctx: &BasicContext, env: &EvalEnv) -> Shape {
+        let eval = |expr: &ScalarExprNode| eval_uniform(expr, image_ctx, env);
+        match self {
+            Self::Circle(cy, cx, r) => Shape::Circle((eval(cy), eval(cx)), eval(r)),
+            Self::Ellipse(cy, cx, ry, rx) => {
+                Shape::Ellipse((eval(cy), eval(cx)), (eval(ry), eval(rx)))
             }
-        };
-        let upper_bound = match &self.upper_bound {
-            None => None,
-            Some(upper_bound) => {
-                let floating_upper_bound: f64 = *upper_bound.eval(image_ctx).first();
-                Some(floating_upper_bound.round() as u32)
+            Self::Polygon(points) => {
+                Shape::Polygon(points.iter().map(|(y, x)| (eval(y), eval(x))).collect())
             }
+            Self::Rect(y0, x0, y1, x1) => {
+                let upper = |bound: &ScalarExprNode| match env.bounds {
+                    SliceBounds::HalfOpen() => eval(bound),
+                    SliceBounds::Inclusive() => eval(bound) + 1.0,
+                };
+                Shape::Rect((eval(y0), eval(x0)), (upper(y1), upper(x1)))
+            }
+        }
+    }
+}
+
+impl Evalulate<BasicContext> for SliceSelectorNode {
+    fn eval(&self, image_ctx: &BasicContext, env: &EvalEnv) -> BasicContext {
+        let full_range = Box::new(SliceRangeNode {
+            lower_bound: None,
+            upper_bound: None,
+            step: None,
+            span: Span::default(),
+        });
+        let y_slice_range = self.y_slice_range.as_ref().unwrap_or(&full_range);
+        let x_slice_range = self.x_slice_range.as_ref().unwrap_or(&full_range);
+
+        let y_bounds = y_slice_range.eval_bounds(image_ctx.y_bounds(), image_ctx, env);
+        let x_bounds = x_slice_range.eval_bounds(image_ctx.x_bounds(), image_ctx, env);
+        let steps = match (
+            y_slice_range.eval_step(image_ctx, env),
+            x_slice_range.eval_step(image_ctx, env),
+        ) {
+            (Some(y_step), Some(x_step)) => (y_step, x_step),
+            _ => return BasicContext::empty(),
         };
 
-        (lower_bound, upper_bound)
+        let subctx = image_ctx.subcontext(y_bounds, x_bounds);
+        if steps == (1, 1) {
+            subctx
+        } else {
+            let origin = (
+                y_bounds.0.unwrap_or(image_ctx.y_bounds().0),
+                x_bounds.0.unwrap_or(image_ctx.x_bounds().0),
+            );
+            subctx.stride(origin, steps)
+        }
     }
 }
 
 impl Evalulate<AnnotatedFloatContext> for ScalarExprNode {
-    fn eval(&self, image_ctx: &BasicContext) -> AnnotatedFloatContext {
+    fn eval(&self, image_ctx: &BasicContext, env: &EvalEnv) -> AnnotatedFloatContext {
         match &self {
-            Self::ScalarFn(fncall_node) => fncall_node.eval(image_ctx),
-            Self::SubExpr(subexpr_node) => subexpr_node.eval(image_ctx),
-            Self::Scalar(scalar_node) => scalar_node.eval(image_ctx),
-            Self::BinaryOp(binary_op_node) => binary_op_node.eval(image_ctx),
+            Self::ScalarFn(fncall_node) => fncall_node.eval(image_ctx, env),
+            Self::SubExpr(subexpr_node) => subexpr_node.eval(image_ctx, env),
+            Self::Scalar(scalar_node, _) => scalar_node.eval(image_ctx, env),
+            Self::BinaryOp(binary_op_node) => binary_op_node.eval(image_ctx, env),
         }
     }
 }
 
 impl Evalulate<AnnotatedFloatContext> for ScalarFnCall {
-    fn eval(&self, image_ctx: &BasicContext) -> AnnotatedFloatContext {
-        let mut evaluated_args = (*self.args).iter().map(|arg| arg.eval(image_ctx));
+    fn eval(&self, image_ctx: &BasicContext, env: &EvalEnv) -> AnnotatedFloatContext {
+        let mut evaluated_args = (*self.args).iter().map(|arg| arg.eval(image_ctx, env));
 
         match &self.op {
-            ScalarFnOp::Min() => float_ops::min(&evaluated_args.collect()),
-            ScalarFnOp::Max() => float_ops::max(&evaluated_args.collect()),
+            ScalarFnOp::Min() => float_ops::min(&evaluated_args.collect::<Vec<_>>()),
+            ScalarFnOp::Max() => float_ops::max(&evaluated_args.collect::<Vec<_>>()),
             ScalarFnOp::Square() => float_ops::square(&evaluated_args.next().unwrap()),
             ScalarFnOp::Sqrt() => float_ops::sqrt(&evaluated_args.next().unwrap()),
+            ScalarFnOp::Mean() => float_ops::mean(&evaluated_args.next().unwrap()),
+            ScalarFnOp::Sum() => float_ops::sum(&evaluated_args.next().unwrap()),
+            ScalarFnOp::StdDev() => float_ops::stddev(&evaluated_args.next().unwrap()),
+            ScalarFnOp::Rand(stream) => float_ops::rand(image_ctx, env.rand_seed(), *stream),
+            ScalarFnOp::RandN(stream) => float_ops::randn(image_ctx, env.rand_seed(), *stream),
+            ScalarFnOp::Noise2d() => {
+                let args = evaluated_args.collect::<Vec<_>>();
+                float_ops::noise2d(&args[0], &args[1], &args[2], env.seed)
+            }
+            ScalarFnOp::Custom(builtin) => float_ops::custom(
+                image_ctx,
+                builtin.f.as_ref(),
+                &evaluated_args.collect::<Vec<_>>(),
+            ),
         }
     }
 }
 
 impl Evalulate<AnnotatedFloatContext> for ScalarNode {
-    fn eval(&self, image_ctx: &BasicContext) -> AnnotatedFloatContext {
+    fn eval(&self, image_ctx: &BasicContext, env: &EvalEnv) -> AnnotatedFloatContext {
         match &self {
             ScalarNode::Float(n) => AnnotatedFloatContext::like(image_ctx, n),
             ScalarNode::Integer(n) => AnnotatedFloatContext::like(image_ctx, &(*n as f64)),
+            ScalarNode::Time(var) => AnnotatedFloatContext::like(
+                image_ctx,
+                &match var {
+                    TimeVar::T() => env.time.t(),
+                    TimeVar::Frame() => env.time.frame as f64,
+                    TimeVar::Frames() => env.time.frames as f64,
+                },
+            ),
+            ScalarNode::Meta(field) => AnnotatedFloatContext::like(
+                image_ctx,
+                // Missing fields are reported by the check pass.
+                &env.metadata.field(*field).unwrap_or(f64::NAN),
+            ),
             ScalarNode::SelectorScalar(selector_scalar_node) => {
-                AnnotatedFloatContext::like(image_ctx, &selector_scalar_node.eval(image_ctx))
+                AnnotatedFloatContext::like(image_ctx, &selector_scalar_node.eval(image_ctx, env))
             }
             ScalarNode::PixelScalar(pixel_expr, attr_access) => {
                 attrs::access_scalar_annotated_ctx_attr(
-                    &pixel_expr.eval(image_ctx),
+                    &pixel_expr.eval(image_ctx, env),
+                    env.region.unwrap_or_else(|| Region::of(image_ctx)),
+                    env.bounds,
                     &attr_access.key,
                 )
             }
@@ -134,15 +361,19 @@ impl Evalulate<AnnotatedFloatContext> for ScalarNode {
 }
 
 impl Evalulate<f64> for SelectorScalarNode {
-    fn eval(&self, image_ctx: &BasicContext) -> f64 {
-        attrs::access_scalar_attr(&self.selector_ctx.eval(image_ctx), &self.accessed_attr.key)
+    fn eval(&self, image_ctx: &BasicContext, env: &EvalEnv) -> f64 {
+        attrs::access_scalar_attr(
+            &self.selector_ctx.eval(image_ctx, env),
+            &self.accessed_attr.key,
+            env.bounds,
+        )
     }
 }
 
 impl Evalulate<AnnotatedFloatContext> for BinaryScalarOpNode {
-    fn eval(&self, image_ctx: &BasicContext) -> AnnotatedFloatContext {
-        let lhs = self.lhs.eval(image_ctx);
-        let rhs = self.rhs.eval(image_ctx);
+    fn eval(&self, image_ctx: &BasicContext, env: &EvalEnv) -> AnnotatedFloatContext {
+        let lhs = self.lhs.eval(image_ctx, env);
+        let rhs = self.rhs.eval(image_ctx, env);
         match &self.op {
             BinaryOpType::Add() => float_ops::add(&lhs, &rhs),
             BinaryOpType::Sub() => float_ops::sub(&lhs, &rhs),
@@ -153,10 +384,10 @@ impl Evalulate<AnnotatedFloatContext> for BinaryScalarOpNode {
 }
 
 impl Evalulate<BasicContext> for OperatorNode {
-    fn eval(&self, image_ctx: &BasicContext) -> BasicContext {
+    fn eval(&self, image_ctx: &BasicContext, env: &EvalEnv) -> BasicContext {
         match &self {
-            Self::UnaryNegationOp() => float_ops::negate(image_ctx),
-            Self::MatchExprOp(op) => op.eval(image_ctx),
+            Self::UnaryNegationOp(_) => float_ops::negate(image_ctx),
+            Self::MatchExprOp(op) => op.eval(image_ctx, env),
         }
     }
 }
@@ -172,129 +403,342 @@ fn match_compare<T: PartialEq + PartialOrd>(op_type: &MatchOpType, lhs: T, rhs:
     }
 }
 
-impl Evalulate<BasicContext> for MatchExprOpNode {
-    fn eval(&self, image_ctx: &BasicContext) -> BasicContext {
-        let match_comp_lhs = &self.match_value;
-        let (matched_ctx, else_context) = match &self.match_comparator_node {
-            None => (image_ctx.clone(), BasicContext::empty()),
-            Some(match_comparator) => match (match_comp_lhs, &match_comparator.cmp_val) {
-                (
-                    MatchComparisonValue::Scalar(lhs_scalar_expr),
-                    MatchComparisonValue::Scalar(rhs_scalar_expr),
-                ) => {
-                    let lhs_terms: AnnotatedFloatContext = lhs_scalar_expr.eval(image_ctx);
-                    let rhs_terms: AnnotatedFloatContext = rhs_scalar_expr.eval(image_ctx);
-                    let mut matched_ctx = BasicContext::empty();
-                    let mut else_context = BasicContext::empty();
-
-                    for (point, annotation) in lhs_terms.iter_annotations() {
-                        if match_compare(
-                            &match_comparator.op_type,
-                            annotation,
-                            rhs_terms.get_annotation(point).unwrap(),
-                        ) {
-                            matched_ctx.insert(point.clone());
-                        } else {
-                            else_context.insert(point.clone());
-                        }
+// Splits the context into the pixels which satisfy a comparison and those
+// which don't, after cleaning up the matched pixels with any morphology.
+fn partition(
+    match_value: &MatchComparisonValue,
+    match_comparator: Option<&MatchComparatorNode>,
+    mask_morphology_nodes: &[MorphologyNode],
+    image_ctx: &BasicContext,
+    env: &EvalEnv,
+) -> (BasicContext, BasicContext) {
+    let (matched_ctx, else_context) = match match_comparator {
+        None => (image_ctx.clone(), BasicContext::empty()),
+        Some(match_comparator) => match (match_value, &match_comparator.cmp_val) {
+            (
+                MatchComparisonValue::Scalar(lhs_scalar_expr),
+                MatchComparisonValue::Scalar(rhs_scalar_expr),
+            ) => {
+                let lhs_terms: AnnotatedFloatContext = lhs_scalar_expr.eval(image_ctx, env);
+                let rhs_terms: AnnotatedFloatContext = rhs_scalar_expr.eval(image_ctx, env);
+                let mut matched_ctx = BasicContext::empty();
+                let mut else_context = BasicContext::empty();
+
+                for (point, annotation) in lhs_terms.iter_annotations() {
+                    if match_compare(
+                        &match_comparator.op_type,
+                        annotation,
+                        rhs_terms.get_annotation(point).unwrap(),
+                    ) {
+                        matched_ctx.insert(point.clone());
+                    } else {
+                        else_context.insert(point.clone());
                     }
-
-                    (matched_ctx, else_context)
                 }
-                (
-                    MatchComparisonValue::Pixel(lhs_pixel_expr),
-                    MatchComparisonValue::Pixel(rhs_pixel_expr),
-                ) => {
-                    let lhs_terms: AnnotatedPixelContext = lhs_pixel_expr.eval(image_ctx);
-                    let rhs_terms: AnnotatedPixelContext = rhs_pixel_expr.eval(image_ctx);
-                    let mut matched_ctx = BasicContext::empty();
-                    let mut else_context = BasicContext::empty();
-
-                    for (point, annotation) in lhs_terms.iter_annotations() {
-                        if match_compare(
-                            &match_comparator.op_type,
-                            annotation,
-                            rhs_terms.get_annotation(point).unwrap(),
-                        ) {
-                            matched_ctx.insert(annotation.clone());
-                        } else {
-                            else_context.insert(annotation.clone());
-                        }
-                    }
 
-                    (matched_ctx, else_context)
+                (matched_ctx, else_context)
+            }
+            (
+                MatchComparisonValue::Pixel(lhs_pixel_expr),
+                MatchComparisonValue::Pixel(rhs_pixel_expr),
+            ) => {
+                let lhs_terms: AnnotatedPixelContext = lhs_pixel_expr.eval(image_ctx, env);
+                let rhs_terms: AnnotatedPixelContext = rhs_pixel_expr.eval(image_ctx, env);
+                let mut matched_ctx = BasicContext::empty();
+                let mut else_context = BasicContext::empty();
+
+                for (point, annotation) in lhs_terms.iter_annotations() {
+                    if match_compare(
+                        &match_comparator.op_type,
+                        annotation,
+                        rhs_terms.get_annotation(point).unwrap(),
+                    ) {
+                        matched_ctx.insert(annotation.clone());
+                    } else {
+                        else_context.insert(annotation.clone());
+                    }
                 }
-                _ => panic!("match terms have incompatible types"),
-            },
-        };
-        let matched_outputs = self.match_return_value_node.eval(&matched_ctx);
+
+                (matched_ctx, else_context)
+            }
+            _ => panic!("match terms have incompatible types"),
+        },
+    };
+
+    if mask_morphology_nodes.is_empty() {
+        (matched_ctx, else_context)
+    } else {
+        let mask = mask_morphology_nodes
+            .iter()
+            .fold(matched_ctx, |mask, morphology| {
+                morphology.apply_to_mask(image_ctx, &mask, env)
+            });
+        (image_ctx.select(mask.clone()), image_ctx.exclude(&mask))
+    }
+}
+
+impl Evalulate<BasicContext> for MatchExprOpNode {
+    fn eval(&self, image_ctx: &BasicContext, env: &EvalEnv) -> BasicContext {
+        let (matched_ctx, else_context) = partition(
+            &self.match_value,
+            self.match_comparator_node.as_ref(),
+            &self.mask_morphology_nodes,
+            image_ctx,
+            env,
+        );
+        let matched_outputs = self.match_return_value_node.eval(&matched_ctx, env);
 
         if let Some(else_block) = &self.else_return_value_node {
-            BasicContext::from_contexts(vec![matched_outputs, else_block.eval(&else_context)])
+            BasicContext::from_contexts(vec![matched_outputs, else_block.eval(&else_context, env)])
         } else {
             matched_outputs
         }
     }
 }
 
+impl Evalulate<AnnotatedFloatContext> for MaskExprNode {
+    fn eval(&self, image_ctx: &BasicContext, env: &EvalEnv) -> AnnotatedFloatContext {
+        match self {
+            Self::Condition(condition) => {
+                let (matched_ctx, _) = partition(
+                    &condition.match_value,
+                    Some(&condition.match_comparator_node),
+                    &condition.mask_morphology_nodes,
+                    image_ctx,
+                    env,
+                );
+                ctx_ops::mask_weights(image_ctx, &matched_ctx)
+            }
+            Self::Named(name, _) => env.mask(name).clone(),
+            Self::Union(l, r) => float_ops::max(&[l.eval(image_ctx, env), r.eval(image_ctx, env)]),
+            Self::Intersection(l, r) => {
+                float_ops::min(&[l.eval(image_ctx, env), r.eval(image_ctx, env)])
+            }
+            Self::Difference(l, r) => {
+                let inverted = Self::Invert(r.clone(), r.span()).eval(image_ctx, env);
+                float_ops::min(&[l.eval(image_ctx, env), inverted])
+            }
+            Self::Invert(mask, _) => {
+                let mask = mask.eval(image_ctx, env);
+                float_ops::sub(&AnnotatedFloatContext::like(&mask, &1.0), &mask)
+            }
+            Self::Grow(mask, radius, element, _) => ctx_ops::grow_mask(
+                &mask.eval(image_ctx, env),
+                eval_uniform(radius, image_ctx, env).round() as i64,
+                *element,
+            ),
+            Self::Shrink(mask, radius, element, _) => ctx_ops::shrink_mask(
+                &mask.eval(image_ctx, env),
+                eval_uniform(radius, image_ctx, env).round() as i64,
+                *element,
+            ),
+            Self::Feather(mask, sigma, _) => ctx_ops::feather_mask(
+                &mask.eval(image_ctx, env),
+                eval_uniform(sigma, image_ctx, env),
+            ),
+        }
+    }
+}
+
 impl Evalulate<BasicContext> for MatchReturnValue {
-    fn eval(&self, image_ctx: &BasicContext) -> BasicContext {
+    fn eval(&self, image_ctx: &BasicContext, env: &EvalEnv) -> BasicContext {
         match self {
             MatchReturnValue::Pixel(pixel_expr) => BasicContext::from_iter(
                 pixel_expr
-                    .eval(image_ctx)
+                    .eval(image_ctx, env)
                     .iter_annotations()
                     .map(|(_, annotation)| annotation)
                     .cloned(),
                 |annotation| annotation,
             ),
-            MatchReturnValue::Operator(operator) => operator.eval(image_ctx),
+            MatchReturnValue::Operator(operator) => operator.eval(image_ctx, env),
         }
     }
 }
 
 impl Evalulate<AnnotatedPixelContext> for PixelExprType {
-    fn eval(&self, image_ctx: &BasicContext) -> AnnotatedPixelContext {
+    fn eval(&self, image_ctx: &BasicContext, env: &EvalEnv) -> AnnotatedPixelContext {
         match self {
-            PixelExprType::Explicit(pixelexpr) => pixelexpr.eval(image_ctx),
-            PixelExprType::CurrentPixel() => {
+            PixelExprType::Explicit(pixelexpr) => pixelexpr.eval(image_ctx, env),
+            PixelExprType::CurrentPixel(_) => {
                 AnnotatedPixelContext::from_iter_with_annotation(image_ctx.iter(), |point| {
                     (point.clone(), point.clone())
                 })
             }
-            PixelExprType::FnCall(pixel_fn_call) => pixel_fn_call.eval(image_ctx),
+            PixelExprType::FnCall(pixel_fn_call) => pixel_fn_call.eval(image_ctx, env),
         }
     }
 }
 
-impl Evalulate<AnnotatedPixelContext> for PixelFnCall {
-    fn eval(&self, image_ctx: &BasicContext) -> AnnotatedPixelContext {
-        let mut evaluated_args = (*self.args).iter().map(|arg| arg.eval(image_ctx));
+impl MorphologyNode {
+    fn apply_to_pixels(
+        &self,
+        arg: &AnnotatedPixelContext,
+        image_ctx: &BasicContext,
+        env: &EvalEnv,
+    ) -> AnnotatedPixelContext {
+        let radius = eval_uniform(&self.radius, image_ctx, env).round() as i64;
+        let erode =
+            |ctx: &AnnotatedPixelContext| ctx_ops::erode(ctx, radius, self.element, env.edge_mode);
+        let dilate =
+            |ctx: &AnnotatedPixelContext| ctx_ops::dilate(ctx, radius, self.element, env.edge_mode);
         match self.op {
-            PixelFnOp::Center() => ctx_ops::center(image_ctx),
-            PixelFnOp::Neighbors(dy, dx) => {
-                ctx_ops::neighbors(&evaluated_args.next().unwrap(), dy, dx)
-            }
-            PixelFnOp::ColorScale(scale_factor) => {
-                ctx_ops::color_scale(&evaluated_args.next().unwrap(), scale_factor)
-            }
-            PixelFnOp::ColorAdd() => ctx_ops::color_add(&evaluated_args.collect()),
+            MorphOp::Erode() => erode(arg),
+            MorphOp::Dilate() => dilate(arg),
+            MorphOp::Open() => dilate(&erode(arg)),
+            MorphOp::Close() => erode(&dilate(arg)),
+        }
+    }
+
+    fn apply_to_mask(
+        &self,
+        image_ctx: &BasicContext,
+        mask: &BasicContext,
+        env: &EvalEnv,
+    ) -> BasicContext {
+        let radius = eval_uniform(&self.radius, image_ctx, env).round() as i64;
+        let erode =
+            |mask: &BasicContext| ctx_ops::erode_mask(image_ctx, mask, radius, self.element);
+        let dilate =
+            |mask: &BasicContext| ctx_ops::dilate_mask(image_ctx, mask, radius, self.element);
+        match self.op {
+            MorphOp::Erode() => erode(mask),
+            MorphOp::Dilate() => dilate(mask),
+            MorphOp::Open() => dilate(&erode(mask)),
+            MorphOp::Close() => erode(&dilate(mask)),
+        }
+    }
+}
+
+// Filter parameters must be the same for the whole context, which the check
+// pass makes sure of, so only the value at the top left pixel is used.
+fn eval_uniform(expr: &ScalarExprNode, image_ctx: &BasicContext, env: &EvalEnv) -> f64 {
+    expr.eval(image_ctx, env).first().copied().unwrap_or(0.0)
+}
+
+impl Evalulate<AnnotatedPixelContext> for PixelFnCall {
+    fn eval(&self, image_ctx: &BasicContext, env: &EvalEnv) -> AnnotatedPixelContext {
+        let mut evaluated_args = (*self.args).iter().map(|arg| arg.eval(image_ctx, env));
+        match &self.op {
+            PixelFnOp::Center() => ctx_ops::center(image_ctx, env.bounds),
+            PixelFnOp::Neighbors(dy, dx, edge_mode) => ctx_ops::neighbors(
+                &evaluated_args.next().unwrap(),
+                &dy.eval(image_ctx, env),
+                &dx.eval(image_ctx, env),
+                edge_mode.unwrap_or(env.edge_mode),
+            ),
+            PixelFnOp::Prev(n) => ctx_ops::previous_frame(
+                &evaluated_args.next().unwrap(),
+                env.earlier_input(
+                    n.as_ref()
+                        .map_or(1.0, |n| eval_uniform(n, image_ctx, env))
+                        .round()
+                        .max(0.0) as u32,
+                ),
+            ),
+            PixelFnOp::ColorScale(scale_factors) => ctx_ops::color_scale(
+                &evaluated_args.next().unwrap(),
+                &scale_factors
+                    .iter()
+                    .map(|scale_factor| scale_factor.eval(image_ctx, env))
+                    .collect::<Vec<_>>(),
+            ),
+            PixelFnOp::ColorAdd(channels) => ctx_ops::color_add(
+                &evaluated_args.collect::<Vec<_>>(),
+                channels.includes_alpha(),
+            ),
+            PixelFnOp::ColorSub(channels) => ctx_ops::color_sub(
+                &evaluated_args.collect::<Vec<_>>(),
+                channels.includes_alpha(),
+            ),
+            PixelFnOp::ColorMul(channels) => ctx_ops::color_mul(
+                &evaluated_args.collect::<Vec<_>>(),
+                channels.includes_alpha(),
+            ),
+            PixelFnOp::ColorMix(t, channels) => ctx_ops::color_mix(
+                &evaluated_args.next().unwrap(),
+                &evaluated_args.next().unwrap(),
+                &t.eval(image_ctx, env),
+                channels.includes_alpha(),
+            ),
+            PixelFnOp::ColorClamp(lower, upper, channels) => ctx_ops::color_clamp(
+                &evaluated_args.next().unwrap(),
+                &lower.eval(image_ctx, env),
+                &upper.eval(image_ctx, env),
+                channels.includes_alpha(),
+            ),
+            PixelFnOp::ColorAbs() => ctx_ops::color_abs(&evaluated_args.next().unwrap()),
             PixelFnOp::ColorNorm() => ctx_ops::color_norm(&evaluated_args.next().unwrap()),
             PixelFnOp::AlphaBlend(blend) => {
-                ctx_ops::alpha_blend(&evaluated_args.next().unwrap(), blend)
+                ctx_ops::alpha_blend(&evaluated_args.next().unwrap(), &blend.eval(image_ctx, env))
             }
+            PixelFnOp::BoxBlur(r) => ctx_ops::box_blur(
+                &evaluated_args.next().unwrap(),
+                eval_uniform(r, image_ctx, env).round() as i64,
+                env.edge_mode,
+            ),
+            PixelFnOp::GaussianBlur(sigma) => ctx_ops::gaussian_blur(
+                &evaluated_args.next().unwrap(),
+                eval_uniform(sigma, image_ctx, env),
+                env.edge_mode,
+            ),
+            PixelFnOp::UnsharpMask(sigma, amount) => ctx_ops::unsharp_mask(
+                &evaluated_args.next().unwrap(),
+                eval_uniform(sigma, image_ctx, env),
+                eval_uniform(amount, image_ctx, env),
+                env.edge_mode,
+            ),
+            PixelFnOp::Median(r) => ctx_ops::median(
+                &evaluated_args.next().unwrap(),
+                eval_uniform(r, image_ctx, env).round() as i64,
+                env.edge_mode,
+            ),
+            PixelFnOp::Bilateral(sigma_s, sigma_r) => ctx_ops::bilateral(
+                &evaluated_args.next().unwrap(),
+                eval_uniform(sigma_s, image_ctx, env),
+                eval_uniform(sigma_r, image_ctx, env),
+                env.edge_mode,
+            ),
+            PixelFnOp::Morphology(morphology) => {
+                morphology.apply_to_pixels(&evaluated_args.next().unwrap(), image_ctx, env)
+            }
+            PixelFnOp::Quantize(n, options) => ctx_ops::quantize(
+                &evaluated_args.next().unwrap(),
+                eval_uniform(n, image_ctx, env).round().max(0.0) as usize,
+                *options,
+            ),
+            PixelFnOp::Palette(source, options) => ctx_ops::apply_palette(
+                &evaluated_args.next().unwrap(),
+                &source.eval(image_ctx, env),
+                *options,
+            ),
+            PixelFnOp::Custom(builtin) => (builtin.f)(&evaluated_args.collect::<Vec<_>>()),
+        }
+    }
+}
+
+impl Evalulate<Vec<[f64; 3]>> for PaletteSource {
+    fn eval(&self, image_ctx: &BasicContext, env: &EvalEnv) -> Vec<[f64; 3]> {
+        match self {
+            PaletteSource::File(_, colors) => colors.clone(),
+            PaletteSource::Colors(colors) => colors
+                .iter()
+                .map(|(r, g, b)| {
+                    [r, g, b].map(|c| eval_uniform(c, image_ctx, env).clamp(0.0, 255.0))
+                })
+                .collect(),
         }
     }
 }
 
 impl Evalulate<AnnotatedPixelContext> for PixelNode {
-    fn eval(&self, image_ctx: &BasicContext) -> AnnotatedPixelContext {
-        let x_values = self.x_expr.eval(image_ctx);
-        let y_values = self.y_expr.eval(image_ctx);
-        let r_values = self.r_expr.eval(image_ctx);
-        let g_values = self.g_expr.eval(image_ctx);
-        let b_values = self.b_expr.eval(image_ctx);
-        let a_values = self.a_expr.eval(image_ctx);
+    fn eval(&self, image_ctx: &BasicContext, env: &EvalEnv) -> AnnotatedPixelContext {
+        let x_values = self.x_expr.eval(image_ctx, env);
+        let y_values = self.y_expr.eval(image_ctx, env);
+        let r_values = self.r_expr.eval(image_ctx, env);
+        let g_values = self.g_expr.eval(image_ctx, env);
+        let b_values = self.b_expr.eval(image_ctx, env);
+        let a_values = self.a_expr.eval(image_ctx, env);
 
         let mut annotated_ctx = AnnotatedPixelContext::empty();
         for pixel in image_ctx.iter() {
@@ -313,6 +757,6 @@ impl Evalulate<AnnotatedPixelContext> for PixelNode {
             );
         }
 
-        annotated_ctx
+        ctx_ops::saturate(&annotated_ctx, env.overflow)
     }
 }
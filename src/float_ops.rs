@@ -1,3 +1,4 @@
+use crate::builtins::ScalarBuiltinFn;
 use crate::context::*;
 
 fn are_compatible_contexts<T>(a: &Context<T>, b: &Context<T>) -> bool {
@@ -28,7 +29,7 @@ fn min2(a: &AnnotatedFloatContext, b: &AnnotatedFloatContext) -> AnnotatedFloatC
     })
 }
 
-pub fn min(args: &Vec<AnnotatedFloatContext>) -> AnnotatedFloatContext {
+pub fn min(args: &[AnnotatedFloatContext]) -> AnnotatedFloatContext {
     if args.is_empty() {
         AnnotatedFloatContext::empty()
     } else {
@@ -55,7 +56,7 @@ fn max2(a: &AnnotatedFloatContext, b: &AnnotatedFloatContext) -> AnnotatedFloatC
     })
 }
 
-pub fn max(args: &Vec<AnnotatedFloatContext>) -> AnnotatedFloatContext {
+pub fn max(args: &[AnnotatedFloatContext]) -> AnnotatedFloatContext {
     if args.is_empty() {
         AnnotatedFloatContext::empty()
     } else {
@@ -78,6 +79,30 @@ pub fn sqrt(arg: &AnnotatedFloatContext) -> AnnotatedFloatContext {
     })
 }
 
+fn total(arg: &AnnotatedFloatContext) -> f64 {
+    arg.iter_annotations().map(|(_, annot)| annot).sum()
+}
+
+/// Aggregates give every pixel the same value, computed over the whole
+/// context.
+pub fn sum(arg: &AnnotatedFloatContext) -> AnnotatedFloatContext {
+    AnnotatedFloatContext::like(arg, &total(arg))
+}
+
+pub fn mean(arg: &AnnotatedFloatContext) -> AnnotatedFloatContext {
+    AnnotatedFloatContext::like(arg, &(total(arg) / arg.count() as f64))
+}
+
+pub fn stddev(arg: &AnnotatedFloatContext) -> AnnotatedFloatContext {
+    let mean = total(arg) / arg.count() as f64;
+    let variance = arg
+        .iter_annotations()
+        .map(|(_, annot)| (annot - mean).powi(2))
+        .sum::<f64>()
+        / arg.count() as f64;
+    AnnotatedFloatContext::like(arg, &variance.sqrt())
+}
+
 pub fn add(a: &AnnotatedFloatContext, b: &AnnotatedFloatContext) -> AnnotatedFloatContext {
     assert_compatible_contexts(a, b);
     AnnotatedFloatContext::from_iter_with_annotation(a.iter_annotations(), |(pixel, a_annot)| {
@@ -110,6 +135,113 @@ pub fn mul(a: &AnnotatedFloatContext, b: &AnnotatedFloatContext) -> AnnotatedFlo
     })
 }
 
+pub fn custom(
+    ctx: &BasicContext,
+    f: &ScalarBuiltinFn,
+    args: &[AnnotatedFloatContext],
+) -> AnnotatedFloatContext {
+    AnnotatedFloatContext::from_iter_with_annotation(ctx.iter(), |pixel| {
+        let values: Vec<f64> = args
+            .iter()
+            .map(|arg| *arg.get_annotation(pixel).unwrap())
+            .collect();
+        (pixel.clone(), f(&values))
+    })
+}
+
+/// Mixes the bits of `x` so nearby inputs give unrelated outputs (the
+/// splitmix64 finalizer).
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/// Hashes a location within one of the random streams of `seed`. Values only
+/// depend on their inputs, never on evaluation order.
+fn hash(seed: u64, stream: u64, y: u64, x: u64) -> u64 {
+    let h = mix(seed ^ 0x9e3779b97f4a7c15);
+    let h = mix(h ^ stream);
+    let h = mix(h ^ y);
+    mix(h ^ x.rotate_left(32))
+}
+
+/// Maps a hash to a float uniformly distributed in `[0, 1)`.
+fn unit(h: u64) -> f64 {
+    (h >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// A uniform random value in `[0, 1)` for each pixel of `ctx`.
+pub fn rand(ctx: &BasicContext, seed: u64, stream: u64) -> AnnotatedFloatContext {
+    AnnotatedFloatContext::from_iter_with_annotation(ctx.iter(), |pixel| {
+        (
+            pixel.clone(),
+            unit(hash(seed, stream, pixel.y as u64, pixel.x as u64)),
+        )
+    })
+}
+
+/// A standard normally distributed random value for each pixel of `ctx`.
+pub fn randn(ctx: &BasicContext, seed: u64, stream: u64) -> AnnotatedFloatContext {
+    AnnotatedFloatContext::from_iter_with_annotation(ctx.iter(), |pixel| {
+        let h = hash(seed, stream, pixel.y as u64, pixel.x as u64);
+        // Box-Muller, keeping the first sample away from zero.
+        let u1 = 1.0 - unit(h);
+        let u2 = unit(mix(h));
+        let z = (-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos();
+        (pixel.clone(), z)
+    })
+}
+
+/// The stream used for noise gradients, so `noise2d` is the same field
+/// wherever it is called.
+const NOISE_STREAM: u64 = u64::MAX;
+
+fn gradient(seed: u64, iy: i64, ix: i64) -> (f64, f64) {
+    let angle = unit(hash(seed, NOISE_STREAM, iy as u64, ix as u64)) * std::f64::consts::TAU;
+    (angle.sin(), angle.cos())
+}
+
+/// Perlin gradient noise at `(y, x)`, scaled to `[0, 1]`.
+fn perlin(seed: u64, y: f64, x: f64) -> f64 {
+    let (y0, x0) = (y.floor(), x.floor());
+    let (fy, fx) = (y - y0, x - x0);
+    let fade = |t: f64| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+    let corner = |dy: f64, dx: f64| {
+        let (gy, gx) = gradient(seed, (y0 + dy) as i64, (x0 + dx) as i64);
+        gy * (fy - dy) + gx * (fx - dx)
+    };
+    let (sy, sx) = (fade(fy), fade(fx));
+    let top = corner(0.0, 0.0) + sx * (corner(0.0, 1.0) - corner(0.0, 0.0));
+    let bottom = corner(1.0, 0.0) + sx * (corner(1.0, 1.0) - corner(1.0, 0.0));
+    let n = top + sy * (bottom - top);
+    // With unit gradients n is within +-sqrt(1/2).
+    (0.5 + n * std::f64::consts::FRAC_1_SQRT_2).clamp(0.0, 1.0)
+}
+
+/// Smooth noise sampled at `(y, x) / scale`, so features are roughly `scale`
+/// pixels across. Pixels where the scale isn't positive are NaN.
+pub fn noise2d(
+    x: &AnnotatedFloatContext,
+    y: &AnnotatedFloatContext,
+    scale: &AnnotatedFloatContext,
+    seed: u64,
+) -> AnnotatedFloatContext {
+    assert_compatible_contexts(x, y);
+    assert_compatible_contexts(x, scale);
+    AnnotatedFloatContext::from_iter_with_annotation(x.iter_annotations(), |(pixel, x_annot)| {
+        let y_annot = y.get_annotation(pixel).unwrap();
+        let scale_annot = scale.get_annotation(pixel).unwrap();
+        if *scale_annot <= 0.0 {
+            return (pixel.clone(), f64::NAN);
+        }
+        (
+            pixel.clone(),
+            perlin(seed, y_annot / scale_annot, x_annot / scale_annot),
+        )
+    })
+}
+
 pub fn negate(arg: &BasicContext) -> BasicContext {
     BasicContext::from_iter(arg.iter(), |pixel| pixel.negate())
 }
@@ -1,5 +1,11 @@
 use crate::ast::IqAstRootNode;
-use crate::eval::Evalulate;
+use crate::builtins::Builtins;
+use crate::check::Diagnostic;
+use crate::context::{EdgeMode, OverflowPolicy, SliceBounds};
+use crate::eval::{EvalEnv, Evalulate, FrameTime};
+use crate::metadata::Metadata;
+use std::cell::Cell;
+use std::rc::Rc;
 
 #[macro_use]
 extern crate lalrpop_util;
@@ -9,15 +15,240 @@ lalrpop_mod!(#[allow(clippy::all)] pub iqparser);
 #[allow(clippy::large_enum_variant)]
 mod ast;
 mod attrs;
+pub mod builtins;
+pub mod check;
 pub mod context;
 mod ctx_ops;
+pub mod diff;
 mod eval;
 mod float_ops;
+pub mod metadata;
+#[cfg(test)]
+mod test_ops_properties;
 
-pub fn execute(input_ctx: context::BasicContext, expressions: String) -> context::BasicContext {
-    let root: IqAstRootNode = iqparser::IqRootParser::new()
-        .parse(expressions.as_str())
-        .unwrap();
+/// The result of running a script: the composited image and the value of
+/// each scalar statement, in order.
+#[derive(Debug, Clone)]
+pub struct Evaluation {
+    pub image: context::BasicContext,
+    pub scalars: Vec<f64>,
+}
+
+/// Parses and evaluates expressions, resolving calls to any functions
+/// registered in its `Builtins`.
+#[derive(Clone, Default)]
+pub struct Engine {
+    builtins: Builtins,
+    overflow: OverflowPolicy,
+    edge_mode: EdgeMode,
+    bounds: SliceBounds,
+    seed: u64,
+    metadata: Rc<Metadata>,
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_builtins(builtins: Builtins) -> Self {
+        Self {
+            builtins,
+            ..Self::default()
+        }
+    }
+
+    /// Sets how out of range channels are handled by pixel constructors and
+    /// when layers are composited.
+    pub fn with_overflow(mut self, overflow: OverflowPolicy) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    pub fn overflow(&self) -> OverflowPolicy {
+        self.overflow
+    }
+
+    /// Sets the edge mode used by `neighbors` calls which don't specify one.
+    pub fn with_edge_mode(mut self, edge_mode: EdgeMode) -> Self {
+        self.edge_mode = edge_mode;
+        self
+    }
+
+    pub fn edge_mode(&self) -> EdgeMode {
+        self.edge_mode
+    }
+
+    /// Sets whether slice upper bounds are excluded (the default) or included
+    /// as they were by earlier versions.
+    pub fn with_slice_bounds(mut self, bounds: SliceBounds) -> Self {
+        self.bounds = bounds;
+        self
+    }
+
+    pub fn slice_bounds(&self) -> SliceBounds {
+        self.bounds
+    }
+
+    /// Sets the seed of `rand()`, `randn()` and `noise2d()`. The same seed
+    /// always produces the same output.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Sets the metadata read by `meta.<field>`.
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = Rc::new(metadata);
+        self
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    pub fn builtins(&self) -> &Builtins {
+        &self.builtins
+    }
 
-    root.eval(&input_ctx)
+    pub fn builtins_mut(&mut self) -> &mut Builtins {
+        &mut self.builtins
+    }
+
+    /// Parses and checks the expressions without evaluating them, returning
+    /// every problem found.
+    pub fn check(&self, expressions: &str) -> Result<(), Vec<Diagnostic>> {
+        self.compile(expressions).map(|_| ())
+    }
+
+    fn compile(&self, expressions: &str) -> Result<IqAstRootNode, Vec<Diagnostic>> {
+        let root = iqparser::IqRootParser::new()
+            .parse(&self.builtins, &Cell::new(0), expressions)
+            .map_err(|error| vec![Diagnostic::from_parse_error(error)])?;
+        let diagnostics = check::check(&root, &self.metadata);
+        if diagnostics.is_empty() {
+            Ok(root)
+        } else {
+            Err(diagnostics)
+        }
+    }
+
+    fn env(&self, time: FrameTime) -> EvalEnv {
+        EvalEnv {
+            overflow: self.overflow,
+            edge_mode: self.edge_mode,
+            bounds: self.bounds,
+            seed: self.seed,
+            time,
+            metadata: self.metadata.clone(),
+            ..EvalEnv::default()
+        }
+    }
+
+    pub fn execute(
+        &self,
+        input_ctx: context::BasicContext,
+        expressions: String,
+    ) -> context::BasicContext {
+        self.evaluate(input_ctx, expressions).image
+    }
+
+    /// Like `execute`, but returns the problems found in the expressions
+    /// instead of panicking.
+    pub fn try_execute(
+        &self,
+        input_ctx: context::BasicContext,
+        expressions: String,
+    ) -> Result<context::BasicContext, Vec<Diagnostic>> {
+        self.try_evaluate(input_ctx, expressions)
+            .map(|evaluation| evaluation.image)
+    }
+
+    /// Like `execute`, but also returns the values of scalar statements such
+    /// as `mean(_.lum);`.
+    pub fn evaluate(&self, input_ctx: context::BasicContext, expressions: String) -> Evaluation {
+        self.try_evaluate(input_ctx, expressions.clone())
+            .unwrap_or_else(|diagnostics| fail(&expressions, &diagnostics))
+    }
+
+    pub fn try_evaluate(
+        &self,
+        input_ctx: context::BasicContext,
+        expressions: String,
+    ) -> Result<Evaluation, Vec<Diagnostic>> {
+        let root = self.compile(expressions.as_str())?;
+        let (image, scalars) = root.eval(&input_ctx, &self.env(FrameTime::default()));
+        Ok(Evaluation { image, scalars })
+    }
+
+    /// Evaluates the expressions against each frame of an animation. `prev`
+    /// reads from earlier input frames.
+    pub fn process_frames(
+        &self,
+        input_frames: Vec<context::BasicContext>,
+        expressions: String,
+    ) -> Vec<context::BasicContext> {
+        self.try_process_frames(input_frames, expressions.clone())
+            .unwrap_or_else(|diagnostics| fail(&expressions, &diagnostics))
+    }
+
+    pub fn try_process_frames(
+        &self,
+        input_frames: Vec<context::BasicContext>,
+        expressions: String,
+    ) -> Result<Vec<context::BasicContext>, Vec<Diagnostic>> {
+        let root = self.compile(expressions.as_str())?;
+        let frames = input_frames.len() as u32;
+        let inputs = Rc::new(input_frames);
+        Ok((0..frames)
+            .map(|frame| {
+                let env = EvalEnv {
+                    inputs: inputs.clone(),
+                    ..self.env(FrameTime { frame, frames })
+                };
+                root.eval(&inputs[frame as usize], &env)
+            })
+            .collect())
+    }
+
+    /// Evaluates the expressions once for each of `frames` frames, with `t`,
+    /// `frame` and `frames` describing the frame being produced.
+    pub fn animate(
+        &self,
+        input_ctx: context::BasicContext,
+        expressions: String,
+        frames: u32,
+    ) -> Vec<context::BasicContext> {
+        self.try_animate(input_ctx, expressions.clone(), frames)
+            .unwrap_or_else(|diagnostics| fail(&expressions, &diagnostics))
+    }
+
+    pub fn try_animate(
+        &self,
+        input_ctx: context::BasicContext,
+        expressions: String,
+        frames: u32,
+    ) -> Result<Vec<context::BasicContext>, Vec<Diagnostic>> {
+        let root = self.compile(expressions.as_str())?;
+        Ok((0..frames)
+            .map(|frame| root.eval(&input_ctx, &self.env(FrameTime { frame, frames })))
+            .collect())
+    }
+}
+
+// The panicking entry points report every problem, as the command line does.
+fn fail(expressions: &str, diagnostics: &[Diagnostic]) -> ! {
+    let rendered: Vec<String> = diagnostics
+        .iter()
+        .map(|diagnostic| diagnostic.render(expressions))
+        .collect();
+    panic!("{}", rendered.join("\n\n"))
+}
+
+pub fn execute(input_ctx: context::BasicContext, expressions: String) -> context::BasicContext {
+    Engine::new().execute(input_ctx, expressions)
 }
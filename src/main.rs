@@ -1,7 +1,16 @@
 use clap::{AppSettings, Arg};
-use iq::context::BasicContext;
+use iq::check::Diagnostic;
+use iq::context::{
+    Animation, BasicContext, DataFormat, EdgeMode, Netpbm, OverflowPolicy, RawLayout, SampleType,
+    SliceBounds,
+};
+use iq::diff;
+use iq::metadata::Metadata;
 use regex::Regex;
-use std::fs;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
 
 fn main() {
     let matches = clap::command!("iq")
@@ -28,11 +37,109 @@ fn main() {
                 .takes_value(true)
                 .help("The expressions to evaluate"),
         )
+        .arg(
+            Arg::with_name("overflow")
+                .long("overflow")
+                .takes_value(true)
+                .possible_values(["clamp", "wrap", "normalize"])
+                .default_value("clamp")
+                .help("How channel values outside of 0-255 are handled"),
+        )
+        .arg(
+            Arg::with_name("edge")
+                .long("edge")
+                .takes_value(true)
+                .possible_values(["zero", "clamp", "wrap", "mirror"])
+                .default_value("clamp")
+                .help("How neighbors reads pixels beyond the edge of the image"),
+        )
+        .arg(
+            Arg::with_name("inclusive_bounds")
+                .long("inclusive-bounds")
+                .help("Include the upper bound of slices like earlier versions of iq"),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .takes_value(true)
+                .default_value("0")
+                .help("Seed for rand(), randn() and noise2d()"),
+        )
+        .arg(
+            Arg::with_name("frames")
+                .long("frames")
+                .takes_value(true)
+                .help("Render an animation of N frames, with t going from 0 towards 1"),
+        )
+        .arg(
+            Arg::with_name("fps")
+                .long("fps")
+                .takes_value(true)
+                .default_value("10")
+                .help("Frames per second of an animation"),
+        )
+        .arg(
+            Arg::with_name("no_auto_orient")
+                .long("no-auto-orient")
+                .help("Keep pixels as stored instead of rotating them by the EXIF orientation"),
+        )
+        .arg(
+            Arg::with_name("crop")
+                .long("crop")
+                .help("Crop the output to its pixels instead of writing them in place"),
+        )
+        .arg(
+            Arg::with_name("output_format")
+                .long("output-format")
+                .takes_value(true)
+                .value_name("format")
+                .possible_values(["image", "json", "csv", "ndjson"])
+                .default_value("image")
+                .help("Write pixel records instead of an image, to stdout if the output is '-'"),
+        )
+        .arg(
+            Arg::with_name("raw")
+                .long("raw")
+                .takes_value(true)
+                .value_name("layout")
+                .help("Read the input as headerless pixels laid out as 'HxW:rgba8' (or gray/rgb, 8/16/f32)"),
+        )
+        .arg(
+            Arg::with_name("npy_dtype")
+                .long("npy-dtype")
+                .takes_value(true)
+                .value_name("dtype")
+                .possible_values(["uint8", "uint16", "float32"])
+                .default_value("uint8")
+                .help("Sample type of .npy outputs"),
+        )
         .arg(Arg::with_name("input_path").help("The path to the input image"))
         .arg(Arg::with_name("output_path").help("Where to write the output image"))
+        .subcommand(
+            clap::Command::new("diff")
+                .about("Compare two images, reporting PSNR, SSIM and per channel errors")
+                .arg(Arg::with_name("a").required(true).help("The first image"))
+                .arg(Arg::with_name("b").required(true).help("The second image"))
+                .arg(
+                    Arg::with_name("out")
+                        .long("out")
+                        .takes_value(true)
+                        .help("Write a heatmap of the differences"),
+                )
+                .arg(
+                    Arg::with_name("threshold")
+                        .long("threshold")
+                        .takes_value(true)
+                        .help("Exit with an error if the mean error of any channel is above this"),
+                ),
+        )
         .get_matches();
 
-    let input_context = match matches.value_of("blank") {
+    if let Some(diff_matches) = matches.subcommand_matches("diff") {
+        std::process::exit(run_diff(diff_matches));
+    }
+
+    let (input, mut metadata) = match matches.value_of("blank") {
         Some(blank_dimensions_string) => {
             if matches.value_of("input_path").is_none() {
                 panic!("Either 'blank' OR an input path should be provided. Not both.")
@@ -45,13 +152,39 @@ fn main() {
             let height = captures.get(1).unwrap().as_str();
             let width = captures.get(2).unwrap().as_str();
 
-            BasicContext::blank(height.parse().unwrap(), width.parse().unwrap())
+            let blank = Animation {
+                frames: vec![BasicContext::blank(
+                    height.parse().unwrap(),
+                    width.parse().unwrap(),
+                )],
+                delays: vec![Duration::ZERO],
+                plays: 1,
+            };
+            (blank, Metadata::default())
         }
-        None => BasicContext::from_path(
-            matches
+        None => {
+            let input_path = matches
                 .value_of("input_path")
-                .expect("Either 'blank' should be specified or an input path"),
-        ),
+                .expect("Either 'blank' should be specified or an input path");
+            let still = |frame: BasicContext| Animation {
+                frames: vec![frame],
+                delays: vec![Duration::ZERO],
+                plays: 1,
+            };
+            if let Some(layout) = matches.value_of("raw") {
+                let layout: RawLayout = layout.parse().unwrap();
+                let data = fs::read(input_path).expect("Input path cannot be read");
+                let frame = BasicContext::from_raw(&data, layout).unwrap();
+                (still(frame), Metadata::default())
+            } else if let Some(frame) = read_still(input_path) {
+                (still(frame), Metadata::default())
+            } else {
+                (
+                    Animation::from_path(input_path),
+                    Metadata::from_path(input_path),
+                )
+            }
+        }
     };
 
     let script_content =
@@ -64,8 +197,300 @@ fn main() {
             )),
         };
 
-    let context = iq::execute(input_context, script_content);
-    if let Some(output_path) = matches.value_of("output_path") {
-        context.write(output_path);
+    let overflow: OverflowPolicy = matches.value_of("overflow").unwrap().parse().unwrap();
+    let edge_mode: EdgeMode = matches.value_of("edge").unwrap().parse().unwrap();
+    let bounds = if matches.is_present("inclusive_bounds") {
+        SliceBounds::Inclusive()
+    } else {
+        SliceBounds::HalfOpen()
+    };
+
+    let seed: u64 = matches
+        .value_of("seed")
+        .unwrap()
+        .parse()
+        .expect("seed should be a non-negative integer");
+
+    let engine = iq::Engine::new()
+        .with_overflow(overflow)
+        .with_edge_mode(edge_mode)
+        .with_slice_bounds(bounds)
+        .with_seed(seed)
+        .with_metadata(metadata.clone());
+
+    let data_format: Option<DataFormat> = match matches.value_of("output_format").unwrap() {
+        "image" => None,
+        format => Some(format.parse().unwrap()),
+    };
+
+    if input.is_animated() {
+        if data_format.is_some() {
+            panic!("--output-format can only be used with a single image")
+        }
+        if matches.is_present("frames") {
+            panic!("--frames can't be used with an animated input")
+        }
+        let mut frames = input.frames;
+        if !matches.is_present("no_auto_orient") {
+            let orientation = metadata.orientation();
+            frames = frames
+                .iter()
+                .map(|frame| frame.orient(orientation))
+                .collect();
+            metadata.reset_orientation();
+        }
+        let output = Animation {
+            frames: or_report(
+                engine.try_process_frames(frames, script_content.clone()),
+                &script_content,
+            )
+            .into_iter()
+            .map(|frame| crop(frame, &matches))
+            .collect(),
+            ..input
+        };
+        if let Some(output_path) = matches.value_of("output_path") {
+            for path in output.write(output_path, overflow) {
+                embed_metadata(&metadata, &path);
+            }
+        }
+        return;
+    }
+
+    let mut input_context = input.frames.into_iter().next().unwrap();
+    if !matches.is_present("no_auto_orient") {
+        input_context = input_context.orient(metadata.orientation());
+        metadata.reset_orientation();
+    }
+
+    match matches.value_of("frames") {
+        Some(frames) => {
+            if data_format.is_some() {
+                panic!("--output-format can't be used with --frames")
+            }
+            let frames: u32 = frames.parse().expect("frames should be a positive integer");
+            let fps: f64 = matches
+                .value_of("fps")
+                .unwrap()
+                .parse()
+                .expect("fps should be a number");
+            if frames == 0 || fps <= 0.0 {
+                panic!("frames and fps should be positive")
+            }
+
+            let contexts: Vec<BasicContext> = or_report(
+                engine.try_animate(input_context, script_content.clone(), frames),
+                &script_content,
+            )
+            .into_iter()
+            .map(|frame| crop(frame, &matches))
+            .collect();
+            if let Some(output_path) = matches.value_of("output_path") {
+                for path in BasicContext::write_frames(&contexts, output_path, fps, overflow) {
+                    embed_metadata(&metadata, &path);
+                }
+            }
+        }
+        None => {
+            let evaluation = or_report(
+                engine.try_evaluate(input_context, script_content.clone()),
+                &script_content,
+            );
+
+            // A script of only scalar statements, like `mean(_.lum);`, has
+            // no pixels worth writing.
+            let context = crop(evaluation.image, &matches);
+            let has_pixels = context.count() > 0 || evaluation.scalars.is_empty();
+            let output_path = matches.value_of("output_path");
+
+            // Pixel records written to stdout have it to themselves, so the
+            // scalars go to stderr rather than making a second document.
+            let records_to_stdout =
+                has_pixels && data_format.is_some() && output_path.is_none_or(|path| path == "-");
+            if records_to_stdout {
+                print_scalars(&mut io::stderr().lock(), &evaluation.scalars, data_format)
+            } else {
+                print_scalars(&mut io::stdout().lock(), &evaluation.scalars, data_format)
+            }
+            .unwrap();
+            if !has_pixels {
+                return;
+            }
+            match (data_format, output_path) {
+                (None, Some(output_path)) => {
+                    let sample: SampleType =
+                        matches.value_of("npy_dtype").unwrap().parse().unwrap();
+                    match still_bytes(&context, output_path, sample, overflow) {
+                        Some(bytes) => fs::write(output_path, bytes).unwrap(),
+                        None => {
+                            context.write_with_overflow(output_path, overflow);
+                            embed_metadata(&metadata, output_path);
+                        }
+                    }
+                }
+                (None, None) => {}
+                (Some(format), Some(output_path)) if output_path != "-" => {
+                    let file = File::create(output_path).expect("Output path cannot be written");
+                    let mut out = BufWriter::new(file);
+                    context.write_data(&mut out, format, overflow).unwrap();
+                    out.flush().unwrap();
+                }
+                (Some(format), _) => {
+                    let mut out = BufWriter::new(io::stdout().lock());
+                    context.write_data(&mut out, format, overflow).unwrap();
+                    out.flush().unwrap();
+                }
+            }
+        }
+    }
+}
+
+fn read_image(path: &str) -> BasicContext {
+    read_still(path).unwrap_or_else(|| BasicContext::from_path(path))
+}
+
+// Metadata which can't be written is left out rather than losing the image.
+fn embed_metadata(metadata: &Metadata, path: &str) {
+    if let Err(error) = metadata.embed_in(path) {
+        eprintln!("Metadata was not written to {}: {}", path, error);
+    }
+}
+
+// A script with problems stops the run after every problem is printed.
+fn or_report<T>(result: Result<T, Vec<Diagnostic>>, script: &str) -> T {
+    result.unwrap_or_else(|diagnostics| {
+        for diagnostic in diagnostics {
+            eprintln!("{}\n", diagnostic.render(script));
+        }
+        std::process::exit(1)
+    })
+}
+
+fn crop(ctx: BasicContext, matches: &clap::ArgMatches) -> BasicContext {
+    if matches.is_present("crop") {
+        ctx.cropped()
+    } else {
+        ctx
+    }
+}
+
+// Returns the exit code: 1 when the images differ by more than the threshold
+// and 2 when they can't be compared.
+fn run_diff(matches: &clap::ArgMatches) -> i32 {
+    let a = read_image(matches.value_of("a").unwrap());
+    let b = read_image(matches.value_of("b").unwrap());
+    let threshold: Option<f64> = matches
+        .value_of("threshold")
+        .map(|threshold| threshold.parse().expect("threshold should be a number"));
+
+    let comparison = match diff::compare(&a, &b) {
+        Ok(comparison) => comparison,
+        Err(message) => {
+            eprintln!("{}", message);
+            return 2;
+        }
+    };
+    if let Some(out) = matches.value_of("out") {
+        diff::heatmap(&a, &b).write(out);
+    }
+
+    println!("channel  max  mean");
+    for (name, channel) in ["r", "g", "b", "a"].iter().zip(comparison.channels) {
+        println!("{:<7}  {:>3}  {:.4}", name, channel.max, channel.mean);
+    }
+    println!("psnr     {:.4} dB", comparison.psnr);
+    println!("ssim     {:.6}", comparison.ssim);
+
+    match threshold {
+        Some(threshold) if comparison.mean_error() > threshold => {
+            eprintln!(
+                "Mean error {:.4} is above the threshold {}",
+                comparison.mean_error(),
+                threshold
+            );
+            1
+        }
+        _ => 0,
+    }
+}
+
+fn extension(path: &str) -> String {
+    Path::new(path).extension().map_or(String::new(), |ext| {
+        ext.to_string_lossy().to_ascii_lowercase()
+    })
+}
+
+// Formats which are read by iq itself rather than the image crate.
+fn read_still(path: &str) -> Option<BasicContext> {
+    let read = || fs::read(path).expect("Input path cannot be read");
+    let frame = match extension(path).as_str() {
+        "npy" => BasicContext::from_npy_bytes(&read()),
+        "pbm" | "pgm" | "ppm" | "pnm" => BasicContext::from_netpbm_bytes(&read()),
+        "pfm" => BasicContext::from_pfm_bytes(&read()),
+        "txt" => BasicContext::from_ascii_art(&String::from_utf8_lossy(&read())),
+        _ => return None,
+    };
+    Some(frame.unwrap())
+}
+
+// Binary netpbm formats are written, the plain ones are only available
+// through the library.
+fn still_bytes(
+    context: &BasicContext,
+    path: &str,
+    sample: SampleType,
+    overflow: OverflowPolicy,
+) -> Option<Vec<u8>> {
+    let bytes = match extension(path).as_str() {
+        "npy" => context.to_npy_bytes(sample, overflow),
+        "pbm" => context.to_netpbm_bytes(Netpbm::P4(), overflow),
+        "pgm" => context.to_netpbm_bytes(Netpbm::P5(), overflow),
+        "ppm" | "pnm" => context.to_netpbm_bytes(Netpbm::P6(), overflow),
+        "pfm" => context.to_pfm_bytes(overflow),
+        "txt" => context.to_ascii_art(overflow).into_bytes(),
+        _ => return None,
+    };
+    Some(bytes)
+}
+
+// Scalars are printed one per line, or in the requested format. JSON has no
+// representation for NaN or infinities, so they become null.
+fn print_scalars<W: Write>(
+    out: &mut W,
+    scalars: &[f64],
+    format: Option<DataFormat>,
+) -> io::Result<()> {
+    if scalars.is_empty() {
+        return Ok(());
+    }
+    let json = |value: &f64| {
+        if value.is_finite() {
+            value.to_string()
+        } else {
+            String::from("null")
+        }
+    };
+    match format {
+        None => {
+            for value in scalars {
+                writeln!(out, "{}", value)?;
+            }
+        }
+        Some(DataFormat::Csv()) => {
+            writeln!(out, "value")?;
+            for value in scalars {
+                writeln!(out, "{}", value)?;
+            }
+        }
+        Some(DataFormat::Json()) => {
+            let values: Vec<String> = scalars.iter().map(json).collect();
+            writeln!(out, "[{}]", values.join(","))?;
+        }
+        Some(DataFormat::Ndjson()) => {
+            for value in scalars {
+                writeln!(out, "{}", json(value))?;
+            }
+        }
     }
+    Ok(())
 }
@@ -0,0 +1,386 @@
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::fs;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const ICC_HEADER: &[u8] = b"ICC_PROFILE\0";
+const XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp";
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+const ORIENTATION_TAG: u16 = 0x0112;
+const EXIF_IFD_TAG: u16 = 0x8769;
+
+/// A metadata value which can be read by scripts as `meta.<name>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetaField {
+    Orientation(),
+    Iso(),
+    ExposureTime(),
+    FNumber(),
+    FocalLength(),
+    ExposureBias(),
+}
+
+// The EXIF tag of each field, in the order of `MetaField::NAMES`.
+const FIELDS: [(MetaField, u16); 6] = [
+    (MetaField::Orientation(), ORIENTATION_TAG),
+    (MetaField::Iso(), 0x8827),
+    (MetaField::ExposureTime(), 0x829a),
+    (MetaField::FNumber(), 0x829d),
+    (MetaField::FocalLength(), 0x920a),
+    (MetaField::ExposureBias(), 0x9204),
+];
+
+impl FromStr for MetaField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::NAMES
+            .iter()
+            .position(|name| s.eq_ignore_ascii_case(name))
+            .map(|i| FIELDS[i].0)
+            .ok_or_else(|| format!("Unknown metadata field: {:?}", s))
+    }
+}
+
+impl MetaField {
+    pub const NAMES: [&'static str; 6] = [
+        "orientation",
+        "iso",
+        "exposure_time",
+        "f_number",
+        "focal_length",
+        "exposure_bias",
+    ];
+
+    fn index(&self) -> usize {
+        FIELDS.iter().position(|(f, _)| f == self).unwrap()
+    }
+
+    pub fn name(&self) -> &'static str {
+        Self::NAMES[self.index()]
+    }
+
+    fn tag(&self) -> u16 {
+        FIELDS[self.index()].1
+    }
+}
+
+/// The parts of an image file which aren't pixels and are carried through to
+/// the output. `exif` is the TIFF structure without the `Exif\0\0` prefix.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub exif: Option<Vec<u8>>,
+    pub icc: Option<Vec<u8>>,
+    pub xmp: Option<Vec<u8>>,
+}
+
+// Reads the TIFF structure used by EXIF, in either byte order.
+struct Tiff<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        let little_endian = match data.get(0..2)? {
+            b"II" => true,
+            b"MM" => false,
+            _ => return None,
+        };
+        let tiff = Self {
+            data,
+            little_endian,
+        };
+        (tiff.u16(2)? == 42).then_some(tiff)
+    }
+
+    fn u16(&self, at: usize) -> Option<u16> {
+        let bytes = [*self.data.get(at)?, *self.data.get(at + 1)?];
+        Some(if self.little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, at: usize) -> Option<u32> {
+        let bytes: [u8; 4] = self.data.get(at..at + 4)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+
+    // The offset of the 12 byte entry for `tag` in the directory at `ifd`.
+    fn entry(&self, ifd: usize, tag: u16) -> Option<usize> {
+        (0..self.u16(ifd)? as usize)
+            .map(|i| ifd + 2 + 12 * i)
+            .find(|&entry| self.u16(entry) == Some(tag))
+    }
+
+    fn value(&self, entry: usize) -> Option<f64> {
+        let at = entry + 8;
+        match self.u16(entry + 2)? {
+            3 => Some(self.u16(at)? as f64),
+            4 => Some(self.u32(at)? as f64),
+            9 => Some(self.u32(at)? as i32 as f64),
+            5 => {
+                let offset = self.u32(at)? as usize;
+                Some(self.u32(offset)? as f64 / self.u32(offset + 4)? as f64)
+            }
+            10 => {
+                let offset = self.u32(at)? as usize;
+                Some(self.u32(offset)? as i32 as f64 / self.u32(offset + 4)? as i32 as f64)
+            }
+            _ => None,
+        }
+    }
+
+    // Finds `tag` in the first directory or the EXIF directory it points to.
+    fn find(&self, tag: u16) -> Option<usize> {
+        let ifd0 = self.u32(4)? as usize;
+        self.entry(ifd0, tag).or_else(|| {
+            let exif_ifd = self.u32(self.entry(ifd0, EXIF_IFD_TAG)? + 8)? as usize;
+            self.entry(exif_ifd, tag)
+        })
+    }
+}
+
+fn u16_be(bytes: &[u8], at: usize) -> usize {
+    u16::from_be_bytes([bytes[at], bytes[at + 1]]) as usize
+}
+
+fn u32_be(bytes: &[u8], at: usize) -> usize {
+    u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap()) as usize
+}
+
+// The marker and payload of each JPEG segment before the image data.
+fn jpeg_segments(bytes: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut segments = vec![];
+    let mut i = 2;
+    while i + 4 <= bytes.len() && bytes[i] == 0xff && bytes[i + 1] != 0xda {
+        // The length counts its own two bytes, so anything shorter is
+        // malformed.
+        let length = u16_be(bytes, i + 2);
+        if length < 2 {
+            break;
+        }
+        let end = (i + 2 + length).min(bytes.len());
+        segments.push((bytes[i + 1], &bytes[i + 4..end]));
+        i = end;
+    }
+    segments
+}
+
+// The type and data of each PNG chunk.
+fn png_chunks(bytes: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut chunks = vec![];
+    let mut i = PNG_SIGNATURE.len();
+    while i + 8 <= bytes.len() {
+        let end = (i + 8 + u32_be(bytes, i)).min(bytes.len());
+        chunks.push((&bytes[i + 4..i + 8], &bytes[i + 8..end]));
+        i = end + 4;
+    }
+    chunks
+}
+
+fn jpeg_segment(marker: u8, parts: &[&[u8]]) -> Result<Vec<u8>, String> {
+    let len: usize = parts.iter().map(|part| part.len()).sum::<usize>() + 2;
+    let len = u16::try_from(len)
+        .map_err(|_| format!("{} bytes of metadata don't fit in a JPEG segment", len - 2))?;
+    let mut segment = vec![0xff, marker];
+    segment.extend_from_slice(&len.to_be_bytes());
+    for part in parts {
+        segment.extend_from_slice(part);
+    }
+    Ok(segment)
+}
+
+fn png_chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(data);
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(kind);
+    hasher.update(data);
+    chunk.extend_from_slice(&hasher.finalize().to_be_bytes());
+    chunk
+}
+
+impl Metadata {
+    /// Reads the EXIF, ICC profile and XMP of a JPEG or PNG. Other formats
+    /// have no metadata.
+    pub fn from_path(path: &str) -> Self {
+        Self::from_bytes(&fs::read(path).unwrap())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut metadata = Self::default();
+        if bytes.starts_with(&[0xff, 0xd8]) {
+            let mut icc_chunks = vec![];
+            for (marker, payload) in jpeg_segments(bytes) {
+                if marker == 0xe1 && payload.starts_with(EXIF_HEADER) {
+                    metadata.exif = Some(payload[EXIF_HEADER.len()..].to_vec());
+                } else if marker == 0xe1 && payload.starts_with(XMP_HEADER) {
+                    metadata.xmp = Some(payload[XMP_HEADER.len()..].to_vec());
+                } else if marker == 0xe2 && payload.starts_with(ICC_HEADER) {
+                    // Profiles are split over segments numbered from 1.
+                    let chunk = &payload[ICC_HEADER.len()..];
+                    if chunk.len() >= 2 {
+                        icc_chunks.push((chunk[0], &chunk[2..]));
+                    }
+                }
+            }
+            if !icc_chunks.is_empty() {
+                icc_chunks.sort_by_key(|(sequence, _)| *sequence);
+                metadata.icc = Some(
+                    icc_chunks
+                        .into_iter()
+                        .flat_map(|(_, chunk)| chunk.to_vec())
+                        .collect(),
+                );
+            }
+        } else if bytes.starts_with(PNG_SIGNATURE) {
+            for (kind, data) in png_chunks(bytes) {
+                match kind {
+                    b"eXIf" => metadata.exif = Some(data.to_vec()),
+                    b"iCCP" => {
+                        // A profile name, then the compression method.
+                        if let Some(name_end) = data.iter().position(|b| *b == 0) {
+                            let mut icc = vec![];
+                            if ZlibDecoder::new(&data[(name_end + 2).min(data.len())..])
+                                .read_to_end(&mut icc)
+                                .is_ok()
+                            {
+                                metadata.icc = Some(icc);
+                            }
+                        }
+                    }
+                    b"iTXt" if data.starts_with(XMP_KEYWORD) => {
+                        // Keyword, compression flag and method, language and
+                        // translated keyword, then the uncompressed text.
+                        if let Some(fields) = data.get(XMP_KEYWORD.len() + 3..) {
+                            let mut nulls = fields.iter().enumerate().filter(|(_, b)| **b == 0);
+                            if let (Some(_), Some((text_start, _))) = (nulls.next(), nulls.next()) {
+                                metadata.xmp = Some(fields[text_start + 1..].to_vec());
+                            }
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+        metadata
+    }
+
+    pub fn field(&self, field: MetaField) -> Option<f64> {
+        let tiff = Tiff::new(self.exif.as_ref()?)?;
+        tiff.value(tiff.find(field.tag())?)
+    }
+
+    /// The EXIF orientation, from 1 (upright) to 8.
+    pub fn orientation(&self) -> u16 {
+        self.field(MetaField::Orientation())
+            .map_or(1, |orientation| orientation as u16)
+    }
+
+    /// Marks the image as upright, once the orientation has been applied to
+    /// its pixels.
+    pub fn reset_orientation(&mut self) {
+        let Some(exif) = &mut self.exif else {
+            return;
+        };
+        let Some((entry, little_endian)) = Tiff::new(exif)
+            .and_then(|tiff| Some((tiff.find(ORIENTATION_TAG)?, tiff.little_endian)))
+        else {
+            return;
+        };
+        let upright = if little_endian {
+            1u16.to_le_bytes()
+        } else {
+            1u16.to_be_bytes()
+        };
+        // A truncated block may end before the value.
+        if let Some(value) = exif.get_mut(entry + 8..entry + 10) {
+            value.copy_from_slice(&upright);
+        }
+    }
+
+    /// Adds the metadata to a JPEG or PNG file. Other formats are left as they
+    /// are. Fails if the file can't be rewritten or the metadata doesn't fit
+    /// in the format.
+    pub fn embed_in(&self, path: &str) -> Result<(), String> {
+        if *self == Self::default() {
+            return Ok(());
+        }
+        let bytes = fs::read(path).map_err(|error| error.to_string())?;
+        let embedded = if bytes.starts_with(&[0xff, 0xd8]) {
+            self.embed_in_jpeg(&bytes)?
+        } else if bytes.starts_with(PNG_SIGNATURE) {
+            self.embed_in_png(&bytes)
+        } else {
+            return Ok(());
+        };
+        fs::write(path, embedded).map_err(|error| error.to_string())
+    }
+
+    fn embed_in_jpeg(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        let mut segments = vec![];
+        if let Some(exif) = &self.exif {
+            segments.push(jpeg_segment(0xe1, &[EXIF_HEADER, exif])?);
+        }
+        if let Some(xmp) = &self.xmp {
+            segments.push(jpeg_segment(0xe1, &[XMP_HEADER, xmp])?);
+        }
+        if let Some(icc) = &self.icc {
+            // Segments are numbered with a byte, from 1.
+            let chunks: Vec<&[u8]> = icc.chunks(65519).collect();
+            let count = u8::try_from(chunks.len()).map_err(|_| {
+                format!(
+                    "An ICC profile of {} bytes doesn't fit in a JPEG",
+                    icc.len()
+                )
+            })?;
+            for (sequence, chunk) in (1..=count).zip(&chunks) {
+                segments.push(jpeg_segment(
+                    0xe2,
+                    &[ICC_HEADER, &[sequence, count], chunk],
+                )?);
+            }
+        }
+
+        // Metadata follows the JFIF header when there is one.
+        let mut at = 2;
+        if let Some((0xe0, payload)) = jpeg_segments(bytes).first() {
+            at += 4 + payload.len();
+        }
+        Ok([&bytes[..at], &segments.concat(), &bytes[at..]].concat())
+    }
+
+    fn embed_in_png(&self, bytes: &[u8]) -> Vec<u8> {
+        let mut chunks = vec![];
+        if let Some(icc) = &self.icc {
+            let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+            encoder.write_all(icc).unwrap();
+            let data = [b"ICC Profile\0\0".as_slice(), &encoder.finish().unwrap()].concat();
+            chunks.push(png_chunk(b"iCCP", &data));
+        }
+        if let Some(exif) = &self.exif {
+            chunks.push(png_chunk(b"eXIf", exif));
+        }
+        if let Some(xmp) = &self.xmp {
+            let data = [XMP_KEYWORD, b"\0\0\0\0\0", xmp].concat();
+            chunks.push(png_chunk(b"iTXt", &data));
+        }
+
+        // Ancillary chunks go straight after the header chunk.
+        let at = PNG_SIGNATURE.len() + 12 + u32_be(bytes, PNG_SIGNATURE.len());
+        [&bytes[..at], &chunks.concat(), &bytes[at..]].concat()
+    }
+}
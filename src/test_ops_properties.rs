@@ -0,0 +1,471 @@
+use crate::context::{
+    AnnotatedFloatContext, AnnotatedPixelContext, BasicContext, EdgeMode, IqPixel, OverflowPolicy,
+    PaletteOptions, SampleType, Shape, ShapeEdge, SliceBounds, StructuringElement,
+};
+use crate::{ctx_ops, diff, float_ops};
+use proptest::prelude::*;
+
+const MAX_SIDE: u32 = 5;
+
+fn pixel_ctx(h: u32, w: u32, colors: &[[i64; 4]]) -> AnnotatedPixelContext {
+    AnnotatedPixelContext::from_iter_with_annotation(BasicContext::blank(h, w).iter(), |pixel| {
+        let c = colors[(pixel.y * w + pixel.x) as usize];
+        (
+            pixel.clone(),
+            IqPixel {
+                y: pixel.y,
+                x: pixel.x,
+                c,
+            },
+        )
+    })
+}
+
+fn float_ctx(h: u32, w: u32, values: &[f64]) -> AnnotatedFloatContext {
+    AnnotatedFloatContext::from_iter_with_annotation(BasicContext::blank(h, w).iter(), |pixel| {
+        (pixel.clone(), values[(pixel.y * w + pixel.x) as usize])
+    })
+}
+
+fn constant_ctx(h: u32, w: u32, value: f64) -> AnnotatedFloatContext {
+    AnnotatedFloatContext::like(&BasicContext::blank(h, w), &value)
+}
+
+fn locations(h: u32, w: u32) -> impl Iterator<Item = (u32, u32)> {
+    (0..h).flat_map(move |y| (0..w).map(move |x| (y, x)))
+}
+
+fn color_at(ctx: &AnnotatedPixelContext, loc: (u32, u32)) -> [i64; 4] {
+    ctx.get_annotation_at_loc(loc).unwrap().c
+}
+
+fn value_at(ctx: &AnnotatedFloatContext, loc: (u32, u32)) -> f64 {
+    *ctx.get_annotation_at_loc(loc).unwrap()
+}
+
+fn colors(n: usize) -> impl Strategy<Value = (u32, u32, Vec<Vec<[i64; 4]>>)> {
+    (1..=MAX_SIDE, 1..=MAX_SIDE).prop_flat_map(move |(h, w)| {
+        (
+            Just(h),
+            Just(w),
+            prop::collection::vec(
+                prop::collection::vec(prop::array::uniform4(-300i64..300), (h * w) as usize),
+                n,
+            ),
+        )
+    })
+}
+
+fn values(n: usize) -> impl Strategy<Value = (u32, u32, Vec<Vec<f64>>)> {
+    (1..=MAX_SIDE, 1..=MAX_SIDE).prop_flat_map(move |(h, w)| {
+        (
+            Just(h),
+            Just(w),
+            prop::collection::vec(
+                prop::collection::vec(-1000.0f64..1000.0, (h * w) as usize),
+                n,
+            ),
+        )
+    })
+}
+
+proptest! {
+    #[test]
+    fn float_add_matches_reference((h, w, v) in values(2)) {
+        let (a, b) = (float_ctx(h, w, &v[0]), float_ctx(h, w, &v[1]));
+        let sum = float_ops::add(&a, &b);
+        prop_assert_eq!(&sum, &float_ops::add(&b, &a));
+        for loc in locations(h, w) {
+            prop_assert_eq!(value_at(&sum, loc), value_at(&a, loc) + value_at(&b, loc));
+        }
+    }
+
+    #[test]
+    fn float_sub_of_self_is_zero((h, w, v) in values(1)) {
+        let a = float_ctx(h, w, &v[0]);
+        prop_assert_eq!(float_ops::sub(&a, &a), constant_ctx(h, w, 0.0));
+    }
+
+    #[test]
+    fn float_mul_matches_reference((h, w, v) in values(2)) {
+        let (a, b) = (float_ctx(h, w, &v[0]), float_ctx(h, w, &v[1]));
+        let product = float_ops::mul(&a, &b);
+        prop_assert_eq!(&product, &float_ops::mul(&b, &a));
+        prop_assert_eq!(float_ops::mul(&a, &constant_ctx(h, w, 1.0)), a.clone());
+        for loc in locations(h, w) {
+            prop_assert_eq!(value_at(&product, loc), value_at(&a, loc) * value_at(&b, loc));
+        }
+    }
+
+    #[test]
+    fn float_div_matches_reference((h, w, v) in values(2), divisor in 1.0f64..100.0) {
+        let a = float_ctx(h, w, &v[0]);
+        let b = float_ctx(h, w, &v[1].iter().map(|x| x.signum() * divisor).collect::<Vec<_>>());
+        let quotient = float_ops::div(&a, &b);
+        for loc in locations(h, w) {
+            prop_assert_eq!(value_at(&quotient, loc), value_at(&a, loc) / value_at(&b, loc));
+        }
+    }
+
+    #[test]
+    fn float_min_max_match_reference((h, w, v) in values(3)) {
+        let args: Vec<_> = v.iter().map(|v| float_ctx(h, w, v)).collect();
+        let min = float_ops::min(&args);
+        let max = float_ops::max(&args);
+        for loc in locations(h, w) {
+            let expected = args.iter().map(|arg| value_at(arg, loc));
+            prop_assert_eq!(value_at(&min, loc), expected.clone().fold(f64::INFINITY, f64::min));
+            prop_assert_eq!(value_at(&max, loc), expected.fold(f64::NEG_INFINITY, f64::max));
+        }
+    }
+
+    #[test]
+    fn float_sqrt_inverts_square((h, w, v) in values(1)) {
+        let a = float_ctx(h, w, &v[0]);
+        let root = float_ops::sqrt(&float_ops::square(&a));
+        for loc in locations(h, w) {
+            prop_assert!((value_at(&root, loc) - value_at(&a, loc).abs()).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn float_custom_applies_per_pixel((h, w, v) in values(2)) {
+        let (a, b) = (float_ctx(h, w, &v[0]), float_ctx(h, w, &v[1]));
+        prop_assert_eq!(
+            float_ops::custom(&BasicContext::blank(h, w), &|args: &[f64]| args[0] + args[1], &[a.clone(), b.clone()]),
+            float_ops::add(&a, &b)
+        );
+    }
+
+    #[test]
+    fn negate_is_an_involution((h, w, c) in colors(1)) {
+        let ctx = BasicContext::from_iter(pixel_ctx(h, w, &c[0]).iter_annotations(), |(_, annot)| annot.clone());
+        prop_assert_eq!(float_ops::negate(&float_ops::negate(&ctx)), ctx);
+    }
+
+    #[test]
+    fn color_add_matches_reference((h, w, c) in colors(3)) {
+        let args: Vec<_> = c.iter().map(|c| pixel_ctx(h, w, c)).collect();
+        let sum = ctx_ops::color_add(&args, false);
+        let sum_rgba = ctx_ops::color_add(&args, true);
+        for loc in locations(h, w) {
+            let (out, out_rgba) = (color_at(&sum, loc), color_at(&sum_rgba, loc));
+            for i in 0..4 {
+                let expected: i64 = args.iter().map(|arg| color_at(arg, loc)[i]).sum();
+                prop_assert_eq!(out_rgba[i], expected);
+                prop_assert_eq!(out[i], if i < 3 { expected } else { color_at(&args[0], loc)[3] });
+            }
+        }
+    }
+
+    #[test]
+    fn color_add_is_commutative((h, w, c) in colors(2)) {
+        let (a, b) = (pixel_ctx(h, w, &c[0]), pixel_ctx(h, w, &c[1]));
+        prop_assert_eq!(
+            ctx_ops::color_add(&[a.clone(), b.clone()], true),
+            ctx_ops::color_add(&[b, a], true)
+        );
+    }
+
+    #[test]
+    fn color_sub_of_self_is_zero((h, w, c) in colors(1)) {
+        let a = pixel_ctx(h, w, &c[0]);
+        let difference = ctx_ops::color_sub(&[a.clone(), a.clone()], false);
+        for loc in locations(h, w) {
+            prop_assert_eq!(color_at(&difference, loc), [0, 0, 0, color_at(&a, loc)[3]]);
+        }
+    }
+
+    #[test]
+    fn color_mul_by_white_is_identity((h, w, c) in colors(1)) {
+        let a = pixel_ctx(h, w, &c[0]);
+        let white = pixel_ctx(h, w, &vec![[255; 4]; (h * w) as usize]);
+        prop_assert_eq!(ctx_ops::color_mul(&[a.clone(), white], true), a);
+    }
+
+    #[test]
+    fn color_mix_endpoints((h, w, c) in colors(2)) {
+        let (a, b) = (pixel_ctx(h, w, &c[0]), pixel_ctx(h, w, &c[1]));
+        prop_assert_eq!(ctx_ops::color_mix(&a, &b, &constant_ctx(h, w, 0.0), true), a.clone());
+        prop_assert_eq!(ctx_ops::color_mix(&a, &b, &constant_ctx(h, w, 1.0), true), b);
+    }
+
+    #[test]
+    fn color_scale_matches_reference((h, w, c) in colors(1), factors in prop::array::uniform4(-2.0f64..2.0)) {
+        let a = pixel_ctx(h, w, &c[0]);
+        prop_assert_eq!(ctx_ops::color_scale(&a, &[constant_ctx(h, w, 1.0)]), a.clone());
+
+        let factor_ctxs: Vec<_> = factors.iter().map(|f| constant_ctx(h, w, *f)).collect();
+        let scaled_rgb = ctx_ops::color_scale(&a, &factor_ctxs[..3]);
+        let scaled_rgba = ctx_ops::color_scale(&a, &factor_ctxs);
+        for loc in locations(h, w) {
+            let input = color_at(&a, loc);
+            for i in 0..4 {
+                let expected = (input[i] as f64 * factors[i]) as i64;
+                prop_assert_eq!(color_at(&scaled_rgba, loc)[i], expected);
+                prop_assert_eq!(color_at(&scaled_rgb, loc)[i], if i < 3 { expected } else { input[3] });
+            }
+        }
+    }
+
+    #[test]
+    fn color_clamp_and_abs_bound_channels((h, w, c) in colors(1)) {
+        let a = pixel_ctx(h, w, &c[0]);
+        let (lower, upper) = (constant_ctx(h, w, 0.0), constant_ctx(h, w, 255.0));
+        let clamped = ctx_ops::color_clamp(&a, &lower, &upper, false);
+        let clamped_rgba = ctx_ops::color_clamp(&a, &lower, &upper, true);
+        let absolute = ctx_ops::color_abs(&a);
+        for loc in locations(h, w) {
+            // Like the other colour functions, alpha is only changed by the _rgba variant.
+            prop_assert!(color_at(&clamped, loc)[..3].iter().all(|c| (0..=255).contains(c)));
+            prop_assert_eq!(color_at(&clamped, loc)[3], color_at(&a, loc)[3]);
+            prop_assert!(color_at(&clamped_rgba, loc).iter().all(|c| (0..=255).contains(c)));
+            prop_assert!(color_at(&absolute, loc)[..3].iter().all(|c| *c >= 0));
+            prop_assert_eq!(color_at(&absolute, loc)[3], color_at(&a, loc)[3]);
+        }
+    }
+
+    #[test]
+    fn color_norm_spans_full_range((h, w, c) in colors(1)) {
+        let a = pixel_ctx(h, w, &c[0]);
+        let normed = ctx_ops::color_norm(&a);
+        for i in 0..3 {
+            let channel: Vec<i64> = locations(h, w).map(|loc| color_at(&normed, loc)[i]).collect();
+            prop_assert!(channel.iter().all(|c| (0..=255).contains(c)));
+            prop_assert_eq!(*channel.iter().min().unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn alpha_blend_by_one_is_identity((h, w, c) in colors(1)) {
+        let a = pixel_ctx(h, w, &c[0]);
+        prop_assert_eq!(ctx_ops::alpha_blend(&a, &constant_ctx(h, w, 1.0)), a);
+    }
+
+    #[test]
+    fn neighbors_reads_offset_pixel((h, w, c) in colors(1), dy in -1i64..=1, dx in -1i64..=1) {
+        let a = pixel_ctx(h, w, &c[0]);
+        let shifted = ctx_ops::neighbors(&a, &constant_ctx(h, w, dy as f64), &constant_ctx(h, w, dx as f64), EdgeMode::Zero());
+        for (y, x) in locations(h, w) {
+            let (ny, nx) = (y as i64 + dy, x as i64 + dx);
+            if (0..h as i64).contains(&ny) && (0..w as i64).contains(&nx) {
+                prop_assert_eq!(color_at(&shifted, (y, x)), color_at(&a, (ny as u32, nx as u32)));
+            }
+        }
+    }
+
+    #[test]
+    fn center_is_constant((h, w) in (1..=MAX_SIDE, 1..=MAX_SIDE)) {
+        let ctx = BasicContext::blank(h, w);
+        for bounds in [SliceBounds::HalfOpen(), SliceBounds::Inclusive()] {
+            let centered = ctx_ops::center(&ctx, bounds);
+            for (_, annot) in centered.iter_annotations() {
+                prop_assert_eq!(annot, &ctx.center_with_bounds(bounds));
+            }
+        }
+    }
+
+    #[test]
+    fn saturate_brings_channels_into_range((h, w, c) in colors(1)) {
+        let a = pixel_ctx(h, w, &c[0]);
+        for overflow in [OverflowPolicy::Clamp(), OverflowPolicy::Wrap(), OverflowPolicy::Normalize()] {
+            let saturated = ctx_ops::saturate(&a, overflow);
+            for loc in locations(h, w) {
+                prop_assert!(color_at(&saturated, loc).iter().all(|c| (0..=255).contains(c)));
+            }
+        }
+    }
+
+    #[test]
+    fn edge_modes_resolve_within_bounds(i in -50i64..50, lower in 0u32..5, len in 0u32..5) {
+        let bounds = (lower, lower + len);
+        prop_assert_eq!(EdgeMode::Zero().resolve(i, bounds).is_some(), (lower as i64..=(lower + len) as i64).contains(&i));
+        for edge_mode in [EdgeMode::Clamp(), EdgeMode::Wrap(), EdgeMode::Mirror()] {
+            let resolved = edge_mode.resolve(i, bounds).unwrap();
+            prop_assert!(bounds.0 <= resolved && resolved <= bounds.1);
+        }
+    }
+
+    #[test]
+    fn filters_preserve_uniform_contexts(
+        (h, w) in (1..=MAX_SIDE, 1..=MAX_SIDE),
+        c in prop::array::uniform4(0i64..=255),
+        r in 0i64..3,
+        sigma in 0.1f64..3.0,
+    ) {
+        let a = pixel_ctx(h, w, &vec![c; (h * w) as usize]);
+        for edge_mode in [EdgeMode::Clamp(), EdgeMode::Wrap(), EdgeMode::Mirror()] {
+            prop_assert_eq!(&ctx_ops::box_blur(&a, r, edge_mode), &a);
+            prop_assert_eq!(&ctx_ops::gaussian_blur(&a, sigma, edge_mode), &a);
+            prop_assert_eq!(&ctx_ops::unsharp_mask(&a, sigma, 2.0, edge_mode), &a);
+            prop_assert_eq!(&ctx_ops::median(&a, r, edge_mode), &a);
+            prop_assert_eq!(&ctx_ops::bilateral(&a, sigma, 10.0, edge_mode), &a);
+        }
+    }
+
+    #[test]
+    fn median_removes_single_outlier(
+        (h, w) in (3..=MAX_SIDE, 3..=MAX_SIDE),
+        c in prop::array::uniform4(0i64..=255),
+        outlier in prop::array::uniform4(0i64..=255),
+        loc in (0..3u32, 0..3u32),
+    ) {
+        let a = pixel_ctx(h, w, &vec![c; (h * w) as usize]);
+        let mut speckled = vec![c; (h * w) as usize];
+        speckled[(loc.0 * w + loc.1) as usize] = outlier;
+        prop_assert_eq!(ctx_ops::median(&pixel_ctx(h, w, &speckled), 1, EdgeMode::Clamp()), a);
+    }
+
+    #[test]
+    fn morphology_brackets_input((h, w, c) in colors(1), r in 0i64..3) {
+        let a = pixel_ctx(h, w, &c[0]);
+        for element in [StructuringElement::Square(), StructuringElement::Disk()] {
+            let eroded = ctx_ops::erode(&a, r, element, EdgeMode::Clamp());
+            let dilated = ctx_ops::dilate(&a, r, element, EdgeMode::Clamp());
+            for loc in locations(h, w) {
+                for i in 0..4 {
+                    prop_assert!(color_at(&eroded, loc)[i] <= color_at(&a, loc)[i]);
+                    prop_assert!(color_at(&a, loc)[i] <= color_at(&dilated, loc)[i]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mask_morphology_brackets_mask(
+        (h, w) in (1..=MAX_SIDE, 1..=MAX_SIDE),
+        selected in prop::collection::vec(any::<bool>(), (MAX_SIDE * MAX_SIDE) as usize),
+        r in 0i64..3,
+    ) {
+        let domain = BasicContext::blank(h, w);
+        let mask = BasicContext::from_iter(
+            domain.iter().filter(|pixel| selected[(pixel.y * w + pixel.x) as usize]),
+            |pixel| pixel.clone(),
+        );
+        for element in [StructuringElement::Square(), StructuringElement::Disk()] {
+            let eroded = ctx_ops::erode_mask(&domain, &mask, r, element);
+            let dilated = ctx_ops::dilate_mask(&domain, &mask, r, element);
+            for loc in locations(h, w) {
+                prop_assert!(!eroded.contains(loc) || mask.contains(loc));
+                prop_assert!(!mask.contains(loc) || dilated.contains(loc));
+            }
+        }
+    }
+
+    #[test]
+    fn soft_masks_stay_bracketed_in_unit_range(
+        (h, w) in (1..=MAX_SIDE, 1..=MAX_SIDE),
+        weights in prop::collection::vec(0.0f64..=1.0, (MAX_SIDE * MAX_SIDE) as usize),
+        r in 0i64..3,
+        sigma in 0.0f64..3.0,
+    ) {
+        let mask = float_ctx(h, w, &weights);
+        let grown = ctx_ops::grow_mask(&mask, r, StructuringElement::Square());
+        let shrunk = ctx_ops::shrink_mask(&mask, r, StructuringElement::Square());
+        let feathered = ctx_ops::feather_mask(&mask, sigma);
+        for loc in locations(h, w) {
+            prop_assert!(value_at(&shrunk, loc) <= value_at(&mask, loc));
+            prop_assert!(value_at(&mask, loc) <= value_at(&grown, loc));
+            prop_assert!((0.0..=1.0).contains(&value_at(&feathered, loc)));
+        }
+    }
+
+    #[test]
+    fn rect_shapes_match_subcontext(
+        (h, w) in (1..=MAX_SIDE, 1..=MAX_SIDE),
+        (y0, y1) in (0u32..MAX_SIDE, 0u32..MAX_SIDE),
+        (x0, x1) in (0u32..MAX_SIDE, 0u32..MAX_SIDE),
+    ) {
+        let domain = BasicContext::blank(h, w);
+        let shape = Shape::Rect((y0 as f64, x0 as f64), (y1 as f64, x1 as f64));
+        let hard = ctx_ops::shape_weights(&domain, &shape, ShapeEdge::Hard());
+        let smooth = ctx_ops::shape_weights(&domain, &shape, ShapeEdge::Smooth());
+        let expected = domain.subcontext((Some(y0), Some(y1)), (Some(x0), Some(x1)));
+        for loc in locations(h, w) {
+            prop_assert_eq!(hard.contains(loc), expected.contains(loc));
+            prop_assert_eq!(smooth.get_annotation_at_loc(loc).copied(), hard.get_annotation_at_loc(loc).copied());
+        }
+    }
+
+    #[test]
+    fn random_values_are_reproducible_and_in_range(
+        (h, w) in (1..=MAX_SIDE, 1..=MAX_SIDE),
+        seed in any::<u64>(),
+        stream in any::<u64>(),
+        scale in 0.5f64..20.0,
+    ) {
+        let domain = BasicContext::blank(h, w);
+        let uniform = float_ops::rand(&domain, seed, stream);
+        let normal = float_ops::randn(&domain, seed, stream);
+        let xs = AnnotatedFloatContext::from_iter_with_annotation(domain.iter(), |p| (p.clone(), p.x as f64));
+        let ys = AnnotatedFloatContext::from_iter_with_annotation(domain.iter(), |p| (p.clone(), p.y as f64));
+        let noise = float_ops::noise2d(&xs, &ys, &constant_ctx(h, w, scale), seed);
+        prop_assert_eq!(&uniform, &float_ops::rand(&domain, seed, stream));
+        prop_assert_eq!(&noise, &float_ops::noise2d(&xs, &ys, &constant_ctx(h, w, scale), seed));
+        for loc in locations(h, w) {
+            prop_assert!((0.0..1.0).contains(&value_at(&uniform, loc)));
+            prop_assert!(value_at(&normal, loc).is_finite());
+            prop_assert!((0.0..=1.0).contains(&value_at(&noise, loc)));
+        }
+    }
+
+    #[test]
+    fn palettes_only_produce_palette_colours(
+        (h, w, c) in colors(1),
+        n in 1usize..5,
+        dither in prop::sample::select(vec!["none", "floyd_steinberg", "atkinson", "bayer"]),
+        space in prop::sample::select(vec!["rgb", "lab"]),
+    ) {
+        let ctx = pixel_ctx(h, w, &c[0]);
+        let options = PaletteOptions {
+            dither: dither.parse().unwrap(),
+            space: space.parse().unwrap(),
+        };
+        let palette = ctx_ops::median_cut(&ctx, n);
+        prop_assert!(!palette.is_empty() && palette.len() <= n);
+        let quantized = ctx_ops::apply_palette(&ctx, &palette, options);
+        for loc in locations(h, w) {
+            let (before, after) = (color_at(&ctx, loc), color_at(&quantized, loc));
+            prop_assert_eq!(before[3], after[3]);
+            let rgb = [after[0] as f64, after[1] as f64, after[2] as f64];
+            prop_assert!(palette.contains(&rgb));
+        }
+    }
+
+    #[test]
+    fn npy_round_trips_clamped_pixels(
+        (h, w, c) in colors(1),
+        sample in prop::sample::select(vec![SampleType::U8(), SampleType::U16(), SampleType::F32()]),
+    ) {
+        let ctx = BasicContext::from_iter(BasicContext::blank(h, w).iter(), |pixel| IqPixel {
+            c: c[0][(pixel.y * w + pixel.x) as usize],
+            ..pixel.clone()
+        });
+        let bytes = ctx.to_npy_bytes(sample, OverflowPolicy::Clamp());
+        prop_assert_eq!(
+            BasicContext::from_npy_bytes(&bytes).unwrap(),
+            ctx.with_overflow(OverflowPolicy::Clamp())
+        );
+    }
+
+    #[test]
+    fn diffs_are_symmetric_and_zero_for_equal_images((h, w, c) in colors(2)) {
+        let ctx = |colors: &[[i64; 4]]| {
+            BasicContext::from_iter(BasicContext::blank(h, w).iter(), |pixel| IqPixel {
+                c: colors[(pixel.y * w + pixel.x) as usize],
+                ..pixel.clone()
+            })
+            .with_overflow(OverflowPolicy::Clamp())
+        };
+        let (a, b) = (ctx(&c[0]), ctx(&c[1]));
+        let forward = diff::compare(&a, &b).unwrap();
+        prop_assert_eq!(&forward, &diff::compare(&b, &a).unwrap());
+        prop_assert!(forward.ssim <= 1.0 + 1e-9);
+        prop_assert!(forward.channels.iter().all(|channel| channel.mean <= channel.max as f64));
+
+        let same = diff::compare(&a, &a).unwrap();
+        prop_assert_eq!(same.mean_error(), 0.0);
+        prop_assert!((same.ssim - 1.0).abs() < 1e-9);
+        prop_assert!(same.psnr.is_infinite());
+    }
+}
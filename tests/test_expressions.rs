@@ -1,6 +1,17 @@
-use iq::context::BasicContext;
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::AnimationDecoder;
+use iq::builtins::Builtins;
+use iq::check::Span;
+use iq::context::{
+    Animation, AnnotatedPixelContext, BasicContext, IqPixel, Netpbm, OverflowPolicy, RawLayout,
+    SampleType, SliceBounds,
+};
+use iq::diff;
+use iq::metadata::{MetaField, Metadata};
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 fn test_file_path(rel_path: &str) -> PathBuf {
     let root = env!("CARGO_MANIFEST_DIR");
@@ -11,6 +22,28 @@ fn test_file_contents(rel_path: &str) -> String {
     fs::read_to_string(test_file_path(rel_path)).unwrap()
 }
 
+/// Builds a context from a plain netpbm image such as `"P3 1 1 255 0 0 0"`,
+/// or from ASCII art where `@` is black and a space is white.
+fn image(text: &str) -> BasicContext {
+    if text.trim_start().starts_with('P') {
+        BasicContext::from_netpbm_bytes(text.trim_start().as_bytes()).unwrap()
+    } else {
+        BasicContext::from_ascii_art(text.trim_start_matches('\n')).unwrap()
+    }
+}
+
+fn speckled(h: u32, w: u32, speck: (u32, u32)) -> BasicContext {
+    BasicContext::from_iter(BasicContext::blank(h, w).iter(), |pixel| IqPixel {
+        y: pixel.y,
+        x: pixel.x,
+        c: if (pixel.y, pixel.x) == speck {
+            [0, 0, 0, 255]
+        } else {
+            [255, 255, 255, 255]
+        },
+    })
+}
+
 #[test]
 fn handles_empty_input() {
     assert_eq!(
@@ -54,12 +87,1263 @@ fn handles_context_ops() {
             test_file_contents("scripts/color_scale.iq")
         )
     );
+}
+
+#[test]
+fn handles_exact_pixels() {
+    assert_eq!(
+        image("P3 2 1 255  0 0 255  255 0 0"),
+        iq::execute(
+            image("P3 2 1 255  255 0 0  0 0 255"),
+            String::from("_ => p(_.y, _.x, _.b, _.g, _.r)")
+        )
+    );
+    assert_eq!(
+        image(
+            "
+@@  
+@@  
+"
+        ),
+        iq::execute(
+            image(
+                "
+%#.:
+@*-.
+"
+            ),
+            String::from("_.r < 128 => p(_.y, _.x, 0, 0, 0) : p(_.y, _.x, 255, 255, 255)")
+        )
+    );
+}
+
+#[test]
+fn handles_scalar_builtin_params() {
+    assert_eq!(
+        BasicContext::blank(10, 10),
+        iq::execute(
+            BasicContext::blank(10, 10),
+            String::from("_ => neighbors(_, _.y - _.y, 1 - 1)")
+        )
+    );
+    assert_eq!(
+        BasicContext::blank_with_default(10, 10, [0, 0, 0, 255]),
+        iq::execute(
+            BasicContext::blank(10, 10),
+            String::from("_ => color_scale(_, _.x * 0.0)")
+        )
+    );
+    assert_eq!(
+        BasicContext::blank_with_default(10, 10, [255, 255, 255, 0]),
+        iq::execute(
+            BasicContext::blank(10, 10),
+            String::from("_ => alpha_blend(_, _.y - _.y)")
+        )
+    );
+}
+
+#[test]
+fn handles_per_channel_ops() {
+    let cases = [
+        ("_ => color_scale(_, 1.0, 0.0, 0.5)", [255, 0, 127, 255]),
+        (
+            "_ => color_scale(_, 1.0, 1.0, 1.0, 0.0)",
+            [255, 255, 255, 0],
+        ),
+        (
+            "_ => color_sub(_, color_scale(_, 0.2))",
+            [204, 204, 204, 255],
+        ),
+        ("_ => color_sub_rgba(_, _)", [0, 0, 0, 0]),
+        (
+            "_ => color_mul(_, color_scale(_, 0.0, 1.0, 0.0))",
+            [0, 255, 0, 255],
+        ),
+        (
+            "_ => color_mix(_, p(_.y, _.x, 0, 0, 0, 0), 0.5)",
+            [127, 127, 127, 255],
+        ),
+        (
+            "_ => color_mix_rgba(_, p(_.y, _.x, 0, 0, 0, 0), 0.5)",
+            [127, 127, 127, 127],
+        ),
+        ("_ => color_clamp(color_scale(_, -1.0))", [0, 0, 0, 255]),
+        ("_ => color_clamp(_, 10, 20)", [20, 20, 20, 255]),
+        ("_ => color_clamp_rgba(_, 10, 20)", [20, 20, 20, 20]),
+        ("_ => color_abs(color_scale(_, -1.0))", [255, 255, 255, 255]),
+    ];
+    for (expression, expected) in cases {
+        assert_eq!(
+            BasicContext::blank_with_default(10, 10, expected),
+            iq::execute(BasicContext::blank(10, 10), String::from(expression)),
+            "{:}",
+            expression
+        );
+    }
+}
+
+#[test]
+fn handles_neighbor_edge_modes() {
+    let expected_color = |edge_mode: &str, x: i64| match (edge_mode, x) {
+        ("zero", 0) => [0, 0, 0, 0],
+        ("clamp", 0) => [0, 0, 0, 255],
+        ("wrap", 0) => [90, 0, 0, 255],
+        ("mirror", 0) => [10, 0, 0, 255],
+        (_, x) => [(x - 1) * 10, 0, 0, 255],
+    };
+    for edge_mode in ["zero", "clamp", "wrap", "mirror"] {
+        let expected =
+            BasicContext::from_iter(BasicContext::blank(10, 10).iter(), |pixel| IqPixel {
+                y: pixel.y,
+                x: pixel.x,
+                c: expected_color(edge_mode, pixel.x as i64),
+            });
+        assert_eq!(
+            expected,
+            iq::execute(
+                BasicContext::blank(10, 10),
+                format!(
+                    "_ => p(_.y, _.x, _.x * 10, 0, 0) | _ => color_scale(neighbors(_, 0, -1, {:}), 1.0)",
+                    edge_mode
+                )
+            ),
+            "{:}",
+            edge_mode
+        );
+    }
+}
+
+#[test]
+fn handles_filters() {
+    let speckled = speckled(10, 10, (4, 4));
+    assert_eq!(
+        BasicContext::blank(10, 10),
+        iq::execute(speckled.clone(), String::from("_ => median(_, 1)"))
+    );
+    assert_eq!(
+        speckled,
+        iq::execute(speckled.clone(), String::from("_ => box_blur(_, 0)"))
+    );
+    assert_eq!(
+        speckled,
+        iq::execute(
+            speckled.clone(),
+            String::from("_ => bilateral(_, 1.0, 1.0)")
+        )
+    );
+
+    let blurred = iq::execute(speckled.clone(), String::from("_ => box_blur(_, 1)"));
+    assert_eq!(
+        blurred.iter().filter(|pixel| pixel.c[0] == 227).count(),
+        9,
+        "the dark pixel should be spread over its 3x3 neighbourhood"
+    );
+}
+
+#[test]
+fn handles_morphology() {
+    let speckled = speckled(10, 10, (4, 4));
+    let eroded = iq::execute(speckled.clone(), String::from("_ => erode(_, 1)"));
+    assert_eq!(eroded.iter().filter(|pixel| pixel.c[0] == 0).count(), 9);
+    let eroded = iq::execute(speckled.clone(), String::from("_ => erode(_, 1, disk)"));
+    assert_eq!(eroded.iter().filter(|pixel| pixel.c[0] == 0).count(), 5);
+
+    for expression in [
+        "_ => dilate(_, 1)",
+        "_ => close(_, 1)",
+        "_ => close(_, 2, disk)",
+    ] {
+        assert_eq!(
+            BasicContext::blank(10, 10),
+            iq::execute(speckled.clone(), String::from(expression)),
+            "{:}",
+            expression
+        );
+    }
+
+    for expression in [
+        "_ => open(_, 1)",
+        "_.r < 128 @ open(1) => p(_.y, _.x, 255, 0, 0) : _",
+    ] {
+        assert_eq!(
+            speckled,
+            iq::execute(speckled.clone(), String::from(expression)),
+            "{:}",
+            expression
+        );
+    }
+
+    let grown = iq::execute(
+        speckled,
+        String::from("_.r < 128 @ dilate(1) => p(_.y, _.x, 255, 0, 0) : _"),
+    );
+    assert_eq!(grown.iter().filter(|pixel| pixel.c[1] == 0).count(), 9);
+}
+
+#[test]
+fn handles_slice_selectors() {
+    let blank = BasicContext::blank(20, 10);
+    let cases = [
+        ("[5:9, 2:3]", 4),
+        ("[-3:, :]", 30),
+        ("[:, -2:]", 40),
+        ("[-500:, :]", 200),
+        ("[0:[].h:2, ::4]", 30),
+        ("[1::2, 1::2]", 50),
+        ("[25%:75%, :]", 100),
+        ("[-50%:, :50%]", 50),
+    ];
+    for (expression, expected) in cases {
+        assert_eq!(
+            iq::execute(blank.clone(), String::from(expression)).count(),
+            expected,
+            "{:}",
+            expression
+        );
+    }
+
+    // Indices and percentages both count from the start of the axis, or
+    // back from its end when negative, even when it doesn't start at 0.
+    let lower_half = BasicContext::from_iter(blank.iter().filter(|pixel| pixel.y >= 10), |pixel| {
+        pixel.clone()
+    });
+    let cases = [
+        ("[0:2, :]", [10, 11]),
+        ("[0%:20%, :]", [10, 11]),
+        ("[-2:, :]", [18, 19]),
+        ("[-20%:, :]", [18, 19]),
+        ("[-500:2, :]", [10, 11]),
+    ];
+    for (expression, expected) in cases {
+        let mut rows: Vec<u32> = iq::execute(lower_half.clone(), String::from(expression))
+            .iter()
+            .map(|pixel| pixel.y)
+            .collect();
+        rows.sort_unstable();
+        rows.dedup();
+        assert_eq!(rows, expected, "{:}", expression);
+    }
+}
+
+#[test]
+fn handles_slice_bound_conventions() {
+    let blank = BasicContext::blank(20, 10);
+    let corpus = test_file_contents("slices.txt");
+    for line in corpus.lines().filter(|line| !line.starts_with('#')) {
+        let fields: Vec<&str> = line.split(" -> ").collect();
+        let (expression, half_open, inclusive) = (fields[0], fields[1], fields[2]);
+        for (bounds, expected) in [
+            (SliceBounds::HalfOpen(), half_open),
+            (SliceBounds::Inclusive(), inclusive),
+        ] {
+            let output = iq::Engine::new()
+                .with_slice_bounds(bounds)
+                .execute(blank.clone(), String::from(expression));
+            let actual = if output.count() == 0 {
+                String::from("0")
+            } else {
+                let ((min_y, max_y), (min_x, max_x)) = (output.y_bounds(), output.x_bounds());
+                format!(
+                    "{:} {:}..={:} {:}..={:}",
+                    output.count(),
+                    min_y,
+                    max_y,
+                    min_x,
+                    max_x
+                )
+            };
+            assert_eq!(actual, expected, "{:} {:?}", expression, bounds);
+        }
+    }
+
+    let sizes = "_ => p(_.y, _.x, [].h, [].w, center().y, center().x)";
+    for (bounds, expected) in [
+        (SliceBounds::HalfOpen(), [20, 10, 10, 5]),
+        (SliceBounds::Inclusive(), [19, 9, 9, 4]),
+    ] {
+        assert_eq!(
+            BasicContext::blank_with_default(20, 10, expected),
+            iq::Engine::new()
+                .with_slice_bounds(bounds)
+                .execute(blank.clone(), String::from(sizes)),
+            "{:?}",
+            bounds
+        );
+    }
+
+    let cropped = iq::execute(blank, String::from("[5:10, 2:4]"));
+    assert_eq!((cropped.y_bounds(), cropped.x_bounds()), ((5, 9), (2, 3)));
+    let cropped = cropped.cropped();
+    assert_eq!((cropped.y_bounds(), cropped.x_bounds()), ((0, 4), (0, 1)));
+}
+
+#[test]
+fn handles_non_square_write() {
+    let path = std::env::temp_dir().join("iq_handles_non_square_write.png");
+    let path = path.to_str().unwrap();
+    let ctx = iq::execute(
+        BasicContext::blank(3, 7),
+        String::from("_ => p(_.y, _.x, _.x, _.y, 0)"),
+    );
+    ctx.write(path);
+    assert_eq!(ctx, BasicContext::from_path(path));
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn handles_scalar_statements() {
+    let image = speckled(2, 2, (0, 0));
+    let evaluation = iq::Engine::new().evaluate(
+        image.clone(),
+        String::from("mean(_.r); sum(_.a); stddev(_.x); mean(_.lum) * 2;"),
+    );
+    assert_eq!(BasicContext::empty(), evaluation.image);
+    assert_eq!(4, evaluation.scalars.len());
+    assert!((evaluation.scalars[0] - 191.25).abs() < 1e-9);
+    assert!((evaluation.scalars[1] - 1020.0).abs() < 1e-9);
+    assert!((evaluation.scalars[2] - 0.5).abs() < 1e-9);
+    assert!((evaluation.scalars[3] - 382.5).abs() < 1e-9);
+
+    let evaluation = iq::Engine::new().evaluate(image.clone(), String::from("_ => _; mean(_.g);"));
+    assert_eq!(image, evaluation.image);
+    assert_eq!(vec![191.25], evaluation.scalars);
+}
+
+#[test]
+#[should_panic(expected = "Scalar statements should have a single value")]
+fn rejects_per_pixel_scalar_statements() {
+    iq::Engine::new().evaluate(BasicContext::blank(2, 2), String::from("_.x;"));
+}
+
+#[test]
+fn handles_data_output() {
+    let image = speckled(1, 2, (0, 1));
+    let write = |format: &str| {
+        let mut out = vec![];
+        image
+            .write_data(&mut out, format.parse().unwrap(), OverflowPolicy::default())
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    };
+
+    assert_eq!(
+        "y,x,r,g,b,a\n0,0,255,255,255,255\n0,1,0,0,0,255\n",
+        write("csv")
+    );
+    assert_eq!(
+        "[{\"y\":0,\"x\":0,\"r\":255,\"g\":255,\"b\":255,\"a\":255},\
+         {\"y\":0,\"x\":1,\"r\":0,\"g\":0,\"b\":0,\"a\":255}]\n",
+        write("json")
+    );
+    assert_eq!(
+        "{\"y\":0,\"x\":0,\"r\":255,\"g\":255,\"b\":255,\"a\":255}\n\
+         {\"y\":0,\"x\":1,\"r\":0,\"g\":0,\"b\":0,\"a\":255}\n",
+        write("ndjson")
+    );
+    assert!("xml".parse::<iq::context::DataFormat>().is_err());
+}
+
+// Builds a version 1 `.npy` file around the given header dict and data.
+fn npy_bytes(dict: &str, data: &[u8]) -> Vec<u8> {
+    let mut out = b"\x93NUMPY\x01\x00".to_vec();
+    out.extend((dict.len() as u16 + 1).to_le_bytes());
+    out.extend(dict.as_bytes());
+    out.push(b'\n');
+    out.extend(data);
+    out
+}
+
+#[test]
+fn handles_npy_and_raw_input() {
+    let image = speckled(2, 3, (1, 2));
+    for (sample, size) in [("uint8", 1), ("uint16", 2), ("float32", 4)] {
+        let bytes = image.to_npy_bytes(sample.parse().unwrap(), OverflowPolicy::default());
+        // Data starts on a 64 byte boundary.
+        assert_eq!(0, (bytes.len() - 2 * 3 * 4 * size) % 64);
+        assert_eq!(image, BasicContext::from_npy_bytes(&bytes).unwrap());
+    }
+
+    // Grey arrays without a channel axis, big endian samples and greyscale
+    // with alpha.
+    let grey = npy_bytes(
+        "{'descr': '|u1', 'fortran_order': False, 'shape': (1, 2), }",
+        &[0, 200],
+    );
+    let big = npy_bytes(
+        "{'descr': '>u2', 'fortran_order': False, 'shape': (1, 2, 2), }",
+        &[0, 0, 0xff, 0xff, 0xff, 0xff, 0x80, 0x80],
+    );
+    let float = npy_bytes(
+        "{'descr': '<f4', 'fortran_order': False, 'shape': (1, 1, 3), }",
+        &[0.0f32, 0.5, 1.0]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect::<Vec<u8>>(),
+    );
+    let colors = |ctx: BasicContext| {
+        let mut pixels: Vec<IqPixel> = ctx.iter().cloned().collect();
+        pixels.sort_by_key(|pixel| (pixel.y, pixel.x));
+        pixels.iter().map(|pixel| pixel.c).collect::<Vec<_>>()
+    };
+    assert_eq!(
+        vec![[0, 0, 0, 255], [200, 200, 200, 255]],
+        colors(BasicContext::from_npy_bytes(&grey).unwrap())
+    );
+    assert_eq!(
+        vec![[0, 0, 0, 255], [255, 255, 255, 128]],
+        colors(BasicContext::from_npy_bytes(&big).unwrap())
+    );
+    assert_eq!(
+        vec![[0, 128, 255, 255]],
+        colors(BasicContext::from_npy_bytes(&float).unwrap())
+    );
+
+    let fortran = npy_bytes(
+        "{'descr': '|u1', 'fortran_order': True, 'shape': (1, 2), }",
+        &[0, 200],
+    );
+    assert!(BasicContext::from_npy_bytes(&fortran).is_err());
+    let signed = npy_bytes(
+        "{'descr': '<i8', 'fortran_order': False, 'shape': (1, 1), }",
+        &[0; 8],
+    );
+    assert!(BasicContext::from_npy_bytes(&signed).is_err());
+    for header in [
+        "{'descr': '', 'fortran_order': False, 'shape': (1, 1), }",
+        "{'descr': 'éu1', 'fortran_order': False, 'shape': (1, 1), }",
+        "{'descr': '|u1', 'fortran_order': False, 'shape': (4294967296, 4294967296, 4), }",
+        "{'descr': '|u1', 'fortran_order': False, 'shape': (4294967295, 4294967295, 4), }",
+    ] {
+        assert!(BasicContext::from_npy_bytes(&npy_bytes(header, &[0])).is_err());
+    }
+    assert!(BasicContext::from_npy_bytes(&grey[..grey.len() - 1]).is_err());
+    assert!(BasicContext::from_npy_bytes(b"P6 1 1 255").is_err());
+
+    let layout: RawLayout = "1x2:rgb8".parse().unwrap();
+    assert_eq!(
+        RawLayout {
+            h: 1,
+            w: 2,
+            channels: 3,
+            sample: SampleType::U8(),
+        },
+        layout
+    );
+    assert_eq!(
+        vec![[1, 2, 3, 255], [4, 5, 6, 255]],
+        colors(BasicContext::from_raw(&[1, 2, 3, 4, 5, 6], layout).unwrap())
+    );
+    let rgba16: RawLayout = "1x1:rgba16".parse().unwrap();
+    assert_eq!(
+        vec![[255, 0, 1, 255]],
+        colors(BasicContext::from_raw(&[255, 255, 0, 0, 1, 1, 255, 255], rgba16).unwrap())
+    );
+    assert!(BasicContext::from_raw(&[1, 2, 3], layout).is_err());
+    assert!("1x2:bgr8".parse::<RawLayout>().is_err());
+}
+
+#[test]
+fn handles_text_images() {
+    let plain = image("P1\n# a comment\n3 2\n010\n1 0 1\n");
+    assert_eq!(
+        image(
+            "
+ @ 
+@ @
+"
+        ),
+        plain
+    );
+    assert_eq!(plain, image("P2 3 2 1  1 0 1  0 1 0"));
+    assert_eq!(
+        image("P2 2 1 65535  0 32896"),
+        image("P3 2 1 255  0 0 0  128 128 128")
+    );
+
+    let colors = image("P3 2 2 255  255 0 0  0 255 0  0 0 255  10 20 30");
+    for format in ["p1", "p2", "p3", "p4", "p5", "p6"] {
+        let format: Netpbm = format.parse().unwrap();
+        let bytes = colors.to_netpbm_bytes(format, OverflowPolicy::default());
+        let read = BasicContext::from_netpbm_bytes(&bytes).unwrap();
+        // The image crate should agree with how the file is read back.
+        let decoded = image::load_from_memory(&bytes).unwrap().to_rgba8();
+        for pixel in read.iter() {
+            let c = decoded.get_pixel(pixel.x, pixel.y).0;
+            assert_eq!(
+                [c[0] as i64, c[1] as i64, c[2] as i64, c[3] as i64],
+                pixel.c
+            );
+        }
+        if format == Netpbm::P3() || format == Netpbm::P6() {
+            assert_eq!(colors, read);
+        }
+    }
+    let bitmap = colors.to_netpbm_bytes(Netpbm::P1(), OverflowPolicy::default());
+    assert_eq!("P1\n2 2\n1 0\n1 1\n", String::from_utf8(bitmap).unwrap());
+
+    let pfm = colors.to_pfm_bytes(OverflowPolicy::default());
+    assert!(pfm.starts_with(b"PF\n2 2\n-1.0\n"));
+    assert_eq!(colors, BasicContext::from_pfm_bytes(&pfm).unwrap());
+    // Big endian greyscale, stored from the bottom row up.
+    let mut grey = b"Pf\n1 2\n1.0\n".to_vec();
+    grey.extend(0.0f32.to_be_bytes());
+    grey.extend(2.0f32.to_be_bytes());
+    let grey = BasicContext::from_pfm_bytes(&grey).unwrap();
+    assert_eq!([510, 510, 510, 255], grey.get((0, 0)).unwrap().c);
+    assert_eq!([0, 0, 0, 255], grey.get((1, 0)).unwrap().c);
+
+    let art = " .:-=+*#%@\n";
+    assert_eq!(
+        art,
+        BasicContext::from_ascii_art(art)
+            .unwrap()
+            .to_ascii_art(OverflowPolicy::default())
+    );
+    assert!(BasicContext::from_ascii_art("ab").is_err());
+    assert!(BasicContext::from_netpbm_bytes(b"P3 2 1 255 0 0 0").is_err());
+    assert!(BasicContext::from_netpbm_bytes(b"P2 1 1 7 8").is_err());
+    assert!(BasicContext::from_netpbm_bytes(b"P7 1 1").is_err());
+    assert!(BasicContext::from_pfm_bytes(b"PF\n1 1\n-1.0\n").is_err());
+    assert!(BasicContext::from_pfm_bytes(b"PF\n0 1\n-1.0\n").is_err());
+    assert!(BasicContext::from_pfm_bytes(b"Pf\n1 0\n-1.0\n").is_err());
+    assert_eq!(
+        BasicContext::from_netpbm_bytes(b"P2 0 1 255").map(|ctx| ctx.count()),
+        Ok(0)
+    );
+}
+
+#[test]
+fn handles_image_diff() {
+    let a = image("P3 2 1 255  0 0 0  100 100 100");
+    let b = image("P3 2 1 255  0 0 0  110 100 90");
+    let comparison = diff::compare(&a, &b).unwrap();
+    assert_eq!(10, comparison.channels[0].max);
+    assert_eq!(5.0, comparison.channels[0].mean);
+    assert_eq!(0, comparison.channels[1].max);
+    assert_eq!(0, comparison.channels[3].max);
+    assert_eq!(5.0, comparison.mean_error());
+    // The mean squared error is 200 / 6.
+    let psnr = 10.0 * (255.0f64 * 255.0 * 6.0 / 200.0).log10();
+    assert!((comparison.psnr - psnr).abs() < 1e-9);
+    assert!(comparison.ssim < 1.0);
+
+    let same = diff::compare(&a, &a).unwrap();
+    assert_eq!(f64::INFINITY, same.psnr);
+    assert_eq!(1.0, same.ssim);
+
+    // Windows reach the last rows and columns even when the step doesn't.
+    let corner = speckled(13, 13, (12, 12));
+    let ssim = diff::compare(&BasicContext::blank(13, 13), &corner)
+        .unwrap()
+        .ssim;
+    assert!(ssim < 1.0, "{:}", ssim);
+    assert_eq!(0.0, same.mean_error());
+    assert!(diff::compare(&a, &image("P3 1 1 255 0 0 0")).is_err());
+
+    // The largest difference is white and no difference is black.
+    assert_eq!(
+        image("P3 2 1 255  0 0 0  255 255 255"),
+        diff::heatmap(&a, &b)
+    );
+}
+
+#[test]
+fn handles_animation() {
+    let frames = iq::Engine::new().animate(
+        BasicContext::blank(2, 3),
+        String::from("_ => p(_.y, _.x, t * 100, frame, frames)"),
+        4,
+    );
+    assert_eq!(frames.len(), 4);
+    for (i, frame) in frames.iter().enumerate() {
+        assert!(frame
+            .iter()
+            .all(|pixel| pixel.c[..3] == [i as i64 * 25, i as i64, 4]));
+    }
+    // A script which isn't animated is the first of a single frame.
+    assert_eq!(
+        iq::execute(
+            BasicContext::blank(2, 3),
+            String::from("_ => p(_.y, _.x, t, frame, frames)")
+        ),
+        BasicContext::blank_with_default(2, 3, [0, 0, 1, 255])
+    );
+
+    let dir = std::env::temp_dir();
+    let gif = dir.join("iq_handles_animation.gif");
+    let apng = dir.join("iq_handles_animation.png");
+    let numbered = dir.join("iq_handles_animation_%02d.png");
+    for path in [&gif, &apng, &numbered] {
+        BasicContext::write_frames(
+            &frames,
+            path.to_str().unwrap(),
+            10.0,
+            OverflowPolicy::default(),
+        );
+    }
+
+    let decoded = GifDecoder::new(fs::File::open(&gif).unwrap())
+        .unwrap()
+        .into_frames()
+        .collect_frames()
+        .unwrap();
+    assert_eq!(decoded.len(), 4);
+    assert_eq!(decoded[3].buffer().get_pixel(2, 1).0, [75, 3, 4, 255]);
+
+    let decoded = PngDecoder::new(fs::File::open(&apng).unwrap())
+        .unwrap()
+        .apng()
+        .into_frames()
+        .collect_frames()
+        .unwrap();
+    assert_eq!(decoded.len(), 4);
+    assert_eq!(decoded[2].buffer().get_pixel(0, 0).0, [50, 2, 4, 255]);
+
+    for (i, frame) in frames.iter().enumerate() {
+        let path = dir.join(format!("iq_handles_animation_{:02}.png", i));
+        assert_eq!(&BasicContext::from_path(path.to_str().unwrap()), frame);
+        fs::remove_file(path).unwrap();
+    }
+    fs::remove_file(gif).unwrap();
+    fs::remove_file(apng).unwrap();
+}
+
+#[test]
+fn handles_animated_input() {
+    let frames: Vec<BasicContext> = [0, 40, 100]
+        .iter()
+        .map(|level| BasicContext::blank_with_default(2, 3, [*level, *level, *level, 255]))
+        .collect();
+    let delays = [100, 200, 300].map(Duration::from_millis).to_vec();
+    let dir = std::env::temp_dir();
+    for (name, plays) in [
+        ("iq_handles_animated_input.gif", 3),
+        ("iq_handles_animated_input.png", 0),
+    ] {
+        let path = dir.join(name);
+        let path = path.to_str().unwrap();
+        BasicContext::write_animation(&frames, &delays, plays, path, OverflowPolicy::default());
+        let animation = Animation::from_path(path);
+        assert!(animation.is_animated());
+        assert_eq!(animation.frames, frames);
+        assert_eq!(animation.delays, delays);
+        assert_eq!(animation.plays, plays);
+        fs::remove_file(path).unwrap();
+    }
+
+    // Only the application extension sets the loop count, not a comment which
+    // happens to mention it.
+    let path = dir.join("iq_handles_animated_input_comment.gif");
+    let path = path.to_str().unwrap();
+    BasicContext::write_animation(&frames, &delays, 3, path, OverflowPolicy::default());
+    let mut bytes = fs::read(path).unwrap();
+    let blocks = 13
+        + if bytes[10] & 0x80 != 0 {
+            3 << ((bytes[10] & 0x07) + 1)
+        } else {
+            0
+        };
+    let comment = b"\x21\xfe\x0fNETSCAPE2.0\x03\x01\x07\x00\x00";
+    bytes.splice(blocks..blocks, comment.iter().copied());
+    fs::write(path, bytes).unwrap();
+    assert_eq!(Animation::from_path(path).plays, 3);
+    fs::remove_file(path).unwrap();
+
+    let still = Animation::from_path(test_file_path("images/dalle_logo.png").to_str().unwrap());
+    assert!(!still.is_animated());
+
+    // Each frame sees its own index and can read earlier frames.
+    let processed = iq::Engine::new().process_frames(
+        frames.clone(),
+        String::from("_ => p(_.y, _.x, _.r - prev(_).r, prev(_, 2).r, frame)"),
+    );
+    let channels: Vec<[i64; 3]> = processed
+        .iter()
+        .map(|frame| {
+            let c = frame.center().c;
+            [c[0], c[1], c[2]]
+        })
+        .collect();
+    assert_eq!(channels, [[0, 0, 0], [40, 0, 1], [60, 0, 2]]);
+}
+
+// A little endian EXIF block with an orientation, ISO speed and exposure time.
+fn exif_bytes(orientation: u16) -> Vec<u8> {
+    let entry = |tag: u16, kind: u16, value: u32| {
+        [
+            tag.to_le_bytes().as_slice(),
+            &kind.to_le_bytes(),
+            &1u32.to_le_bytes(),
+            &value.to_le_bytes(),
+        ]
+        .concat()
+    };
+    [
+        b"II".as_slice(),
+        &42u16.to_le_bytes(),
+        &8u32.to_le_bytes(),
+        // The first directory, pointing to the EXIF directory at 38.
+        &2u16.to_le_bytes(),
+        &entry(0x0112, 3, orientation as u32),
+        &entry(0x8769, 4, 38),
+        &0u32.to_le_bytes(),
+        // The EXIF directory, with the exposure time stored at 68.
+        &2u16.to_le_bytes(),
+        &entry(0x8827, 3, 400),
+        &entry(0x829a, 5, 68),
+        &0u32.to_le_bytes(),
+        &1u32.to_le_bytes(),
+        &250u32.to_le_bytes(),
+    ]
+    .concat()
+}
+
+#[test]
+fn handles_metadata() {
+    let mut metadata = Metadata {
+        exif: Some(exif_bytes(6)),
+        icc: Some((0..=255).cycle().take(70000).collect()),
+        xmp: Some(b"<x:xmpmeta xmlns:x='adobe:ns:meta/'/>".to_vec()),
+    };
+    assert_eq!(metadata.orientation(), 6);
+    assert_eq!(metadata.field(MetaField::Iso()), Some(400.0));
+    assert_eq!(metadata.field(MetaField::ExposureTime()), Some(0.004));
+    assert_eq!(metadata.field(MetaField::FNumber()), None);
+    metadata.reset_orientation();
+    assert_eq!(metadata.orientation(), 1);
+    assert_eq!(metadata.field(MetaField::Iso()), Some(400.0));
+
+    let ctx = iq::execute(
+        BasicContext::blank(2, 3),
+        String::from("_ => p(_.y, _.x, _.x * 80, _.y * 200, 0)"),
+    );
+    for name in ["iq_handles_metadata.png", "iq_handles_metadata.jpg"] {
+        let path = std::env::temp_dir().join(name);
+        let path = path.to_str().unwrap();
+        ctx.write(path);
+        metadata.embed_in(path).unwrap();
+        assert_eq!(Metadata::from_path(path), metadata);
+        assert_eq!(BasicContext::from_path(path).count(), 6);
+        fs::remove_file(path).unwrap();
+    }
+
+    // JPEG segments are at most 64 KiB, so larger metadata can't be written.
+    let path = std::env::temp_dir().join("iq_handles_metadata_large.jpg");
+    let path = path.to_str().unwrap();
+    ctx.write(path);
+    let large = Metadata {
+        xmp: Some(vec![b' '; 70000]),
+        ..Metadata::default()
+    };
+    assert!(large.embed_in(path).is_err());
+    assert_eq!(Metadata::from_path(path), Metadata::default());
+    fs::remove_file(path).unwrap();
+
+    // A block which ends inside the orientation entry is left as it is.
+    let mut truncated = Metadata {
+        exif: Some(exif_bytes(6)[..19].to_vec()),
+        ..Metadata::default()
+    };
+    truncated.reset_orientation();
+    assert_eq!(truncated.exif, Some(exif_bytes(6)[..19].to_vec()));
+
+    // Malformed files read as having no metadata rather than panicking.
+    for length in [0, 1] {
+        let jpeg = [0xff, 0xd8, 0xff, 0xe1, 0, length, 0xff, 0xd9];
+        assert_eq!(Metadata::from_bytes(&jpeg), Metadata::default());
+    }
+    let png = [
+        b"\x89PNG\r\n\x1a\n".as_slice(),
+        &18u32.to_be_bytes(),
+        b"iTXt",
+        b"XML:com.adobe.xmp\0",
+        &[0; 4],
+    ]
+    .concat();
+    assert_eq!(Metadata::from_bytes(&png), Metadata::default());
+
+    // Orientation 6 is stored rotated a quarter turn anticlockwise.
+    let upright = ctx.orient(6);
+    assert_eq!((upright.height(), upright.width()), (3, 2));
+    assert_eq!(upright.orient(8), ctx);
+    for orientation in 1..=8 {
+        let oriented = ctx.orient(orientation);
+        let undo = [1, 2, 3, 4, 5, 8, 7, 6][orientation as usize - 1];
+        assert_eq!(oriented.orient(undo), ctx, "{:}", orientation);
+    }
+
+    let engine = iq::Engine::new().with_metadata(metadata);
+    let iso = engine.execute(
+        BasicContext::blank(1, 1),
+        String::from("_ => p(_.y, _.x, meta.iso / 4, 1 / meta.exposure_time, 0)"),
+    );
+    assert_eq!(iso.center().c, [100, 250, 0, 255]);
+
+    // Missing fields are found before evaluation.
+    let diagnostics = engine.check("meta.f_number > 2 => _").unwrap_err();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].span, Span::new(0, 13));
+    assert_eq!(
+        diagnostics[0].message,
+        "The input image has no \"f_number\" metadata"
+    );
+}
+
+#[test]
+fn handles_shape_selectors() {
+    let blank = BasicContext::blank(10, 10);
+    assert_eq!(
+        iq::execute(blank.clone(), String::from("[2:5, 3:7]")),
+        iq::execute(blank.clone(), String::from("[rect(2, 3, 5, 7)]"))
+    );
+
+    let cases = [
+        ("[circle(4, 4, 2)]", 13),
+        ("[circle([].h / 2, [].w / 2, 100)]", 100),
+        ("[ellipse(4, 4, 1, 3)]", 9),
+        ("[poly((-0.5, -0.5), (-0.5, 9), (9, -0.5))]", 45),
+        ("[circle(4, 4, 2, hard)]", 13),
+    ];
+    for (expression, expected) in cases {
+        assert_eq!(
+            iq::execute(blank.clone(), String::from(expression)).count(),
+            expected,
+            "{:}",
+            expression
+        );
+    }
+
+    let smooth = iq::execute(
+        blank,
+        String::from("[circle(4.5, 4.5, 3, smooth)] | _ => _"),
+    );
+    assert_eq!(smooth.get((4, 4)).unwrap().c[3], 255);
+    assert!(smooth
+        .iter()
+        .any(|pixel| 0 < pixel.c[3] && pixel.c[3] < 255));
+    assert!(smooth.get((0, 0)).is_none());
+}
+
+#[test]
+fn handles_relative_coordinates() {
+    let relative = iq::execute(
+        BasicContext::blank(10, 10),
+        String::from("[2:6, 3:8] | _ => p(_.y, _.x, _.ry, _.rx, 0)"),
+    );
+    assert_eq!(relative.get((2, 3)).unwrap().c, [0, 0, 0, 255]);
+    assert_eq!(relative.get((5, 7)).unwrap().c, [3, 4, 0, 255]);
+
+    // Match arms measure against the selected region, not the pixels matched.
+    let matched = iq::execute(
+        BasicContext::blank(10, 10),
+        String::from("[2:6, 3:8] | _.y > 3 => p(_.y, _.x, _.ry, _.v * 3, _.radius)"),
+    );
+    assert_eq!(matched.get((4, 3)).unwrap().c, [2, 2, 2, 255]);
+    assert_eq!(matched.get((5, 7)).unwrap().c, [3, 3, 2, 255]);
+
+    let normalized = iq::execute(
+        BasicContext::blank(6, 11),
+        String::from("_ => p(_.y, _.x, _.v * 100, _.u * 100, 0)"),
+    );
+    assert_eq!(normalized.get((0, 0)).unwrap().c, [0, 0, 0, 255]);
+    assert_eq!(normalized.get((5, 10)).unwrap().c, [100, 100, 0, 255]);
+    assert_eq!(normalized.get((1, 5)).unwrap().c, [20, 50, 0, 255]);
+
+    let polar = iq::execute(
+        BasicContext::blank(11, 11),
+        String::from("_ => p(_.y, _.x, _.radius, _.angle / 2, 0)"),
+    );
+    assert_eq!(polar.get((5, 5)).unwrap().c, [0, 0, 0, 255]);
+    assert_eq!(polar.get((5, 8)).unwrap().c, [3, 0, 0, 255]);
+    assert_eq!(polar.get((8, 5)).unwrap().c, [3, 45, 0, 255]);
+    assert_eq!(polar.get((5, 2)).unwrap().c, [3, 90, 0, 255]);
+    assert_eq!(polar.get((2, 5)).unwrap().c, [3, 135, 0, 255]);
+}
+
+#[test]
+fn handles_random_values() {
+    let blank = BasicContext::blank(8, 8);
+    let run = |seed: u64, expression: &str| {
+        iq::Engine::new()
+            .with_seed(seed)
+            .execute(blank.clone(), String::from(expression))
+    };
+
+    let grain = "_ => p(_.y, _.x, rand() * 255, rand() * 255, 128 + randn() * 32)";
+    assert_eq!(run(7, grain), run(7, grain));
+    assert_ne!(run(7, grain), run(8, grain));
+    assert!(run(7, grain).iter().any(|pixel| pixel.c[0] != pixel.c[1]));
+    // Streams are numbered by call, so layout doesn't change the values.
+    let spaced = "_ =>  p(_.y, _.x,\n  rand() * 255, rand() * 255,   128 + randn() * 32)";
+    assert_eq!(run(7, grain), run(7, spaced));
+
+    let noise = "_ => p(_.y, _.x, noise2d(_.x, _.y, 4) * 255, 0, 0)";
+    assert_eq!(run(3, noise), run(3, noise));
+    // Noise is smooth, so neighbouring pixels are close.
+    let noisy = run(3, noise);
+    let red_at = |y: u32, x: u32| noisy.get((y, x)).unwrap().c[0];
+    for y in 0..8 {
+        for x in 1..8 {
+            assert!((red_at(y, x) - red_at(y, x - 1)).abs() < 128);
+        }
+    }
+}
+
+#[test]
+fn handles_palettes() {
+    let gpl = test_file_path("palettes/black_white.gpl");
+    let from_file = format!("_ => palette(_, {:?})", gpl.to_str().unwrap());
+    let from_list = "_ => palette(_, (0, 0, 0), (255, 255, 255))";
+    let dark = BasicContext::blank_with_default(4, 4, [100, 100, 100, 255]);
+    assert_eq!(
+        iq::execute(dark.clone(), from_file.clone()),
+        iq::execute(dark.clone(), String::from(from_list))
+    );
+    assert_eq!(
+        iq::execute(dark.clone(), String::from(from_list)),
+        BasicContext::blank_with_default(4, 4, [0, 0, 0, 255])
+    );
+
+    // Palette files are read when the script is checked.
+    let diagnostics = iq::Engine::new()
+        .check("_ => palette(_, \"missing.gpl\")")
+        .unwrap_err();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].span, Span::new(16, 29));
+    assert!(diagnostics[0]
+        .message
+        .starts_with("Cannot read palette \"missing.gpl\""));
+
+    // Half grey dithers to a mix of both colours, in roughly equal parts.
+    for dither in ["floyd_steinberg", "atkinson", "bayer"] {
+        let dithered = iq::execute(
+            BasicContext::blank_with_default(8, 8, [128, 128, 128, 255]),
+            format!("_ => palette(_, (0, 0, 0), (255, 255, 255), {:})", dither),
+        );
+        let white = dithered.iter().filter(|pixel| pixel.c[0] == 255).count();
+        assert!(dithered
+            .iter()
+            .all(|pixel| pixel.c[0] == 0 || pixel.c[0] == 255));
+        assert!((24..=40).contains(&white), "{:} gave {:}", dither, white);
+    }
+
+    let two_tone = speckled(6, 6, (2, 3));
+    assert_eq!(
+        iq::execute(two_tone.clone(), String::from("_ => quantize(_, 2, lab)")),
+        two_tone
+    );
+    let single = iq::execute(two_tone, String::from("_ => quantize(_, 1)"));
+    assert!(single.iter().all(|pixel| pixel.c == single.center().c));
+}
+
+#[test]
+fn handles_masks() {
+    let speckled = speckled(10, 10, (4, 4));
+    let cases = [
+        ("mask dark = _.r < 128; [dark] | _ => _;", 1),
+        ("mask dark = _.r < 128 @ dilate(1); [dark] | _ => _;", 9),
+        (
+            "mask dark = _.r < 128; mask light = !dark; [light] | _ => _;",
+            99,
+        ),
+        (
+            "mask dark = _.r < 128; mask ring = grow(dark, 1) - dark; [ring];",
+            8,
+        ),
+        (
+            "mask dark = _.r < 128; mask left = _.x < 5; mask m = dark | left; [m];",
+            50,
+        ),
+        (
+            "mask dark = _.r < 128; mask left = _.x < 5; mask m = dark & left; [m];",
+            1,
+        ),
+        (
+            "mask dark = _.r < 128; mask left = _.x < 5; mask m = dark & !left; [m];",
+            0,
+        ),
+        (
+            "mask m = shrink(grow(_.r < 128, 1, disk), 1, disk); [m];",
+            1,
+        ),
+    ];
+    for (expression, expected) in cases {
+        assert_eq!(
+            iq::execute(speckled.clone(), String::from(expression)).count(),
+            expected,
+            "{:}",
+            expression
+        );
+    }
+
+    let feathered = iq::execute(
+        speckled.clone(),
+        String::from("mask soft = feather(_.r < 128, 1); [soft] | _ => p(_.y, _.x, 255, 0, 0);"),
+    );
+    let alpha_at = |y: u32, x: u32| feathered.get((y, x)).unwrap().c[3];
+    assert!(feathered.count() > 9);
+    assert!(alpha_at(4, 4) > alpha_at(4, 5));
+    assert!(alpha_at(4, 5) > alpha_at(4, 6));
+    assert!(feathered.get((0, 0)).is_none());
+}
+
+#[test]
+fn handles_overflow_policies() {
+    let cases = [
+        (OverflowPolicy::Clamp(), [255, 255, 255, 255]),
+        (OverflowPolicy::Wrap(), [50, 50, 50, 255]),
+        (OverflowPolicy::Normalize(), [255, 255, 255, 255]),
+    ];
+    for (overflow, expected) in cases {
+        assert_eq!(
+            BasicContext::blank_with_default(10, 10, expected),
+            iq::Engine::new().with_overflow(overflow).execute(
+                BasicContext::blank(10, 10),
+                String::from("_ => color_scale(_, 1.2)")
+            ),
+            "{:?}",
+            overflow
+        );
+    }
+
+    assert_eq!(
+        BasicContext::blank_with_default(10, 10, [0, 255, 20, 255]),
+        iq::execute(
+            BasicContext::blank(10, 10),
+            String::from("_ => p(_.y, _.x, -10, 300, 20)")
+        )
+    );
+    assert_eq!(
+        BasicContext::blank_with_default(10, 10, [0, 0, 0, 255]),
+        iq::execute(BasicContext::blank(10, 10), String::from("~"))
+    );
+}
+
+#[test]
+fn handles_registered_builtins() {
+    let mut builtins = Builtins::new();
+    builtins
+        .register_scalar("half", |args| args[0] / 2.0)
+        .register_pixel("first", |args: &[AnnotatedPixelContext]| args[0].clone());
+    let engine = iq::Engine::with_builtins(builtins);
+
+    assert_eq!(
+        BasicContext::blank_with_default(10, 10, [100, 100, 100, 255]),
+        engine.execute(
+            BasicContext::blank_with_default(10, 10, [200, 200, 200, 255]),
+            String::from("_ => p(_.y, _.x, half(_.r), half(_.g), half(_.b))")
+        )
+    );
+    assert_eq!(
+        BasicContext::blank(10, 10),
+        engine.execute(
+            BasicContext::blank(10, 10),
+            String::from("first(_) == _ => first(_)")
+        )
+    );
+    assert_eq!(
+        BasicContext::blank_with_default(10, 10, [0, 0, 0, 255]),
+        engine.execute(
+            BasicContext::blank(10, 10),
+            String::from("half(4) + 1 > 2 => color_scale(first(_), 0.0)")
+        )
+    );
+}
 
-    // Just to check sobel doesn't crash.
-    let output_ctx = iq::execute(
-        BasicContext::blank_with_default(10, 10, [255, 255, 255, 255]),
-        test_file_contents("scripts/sobel_edge_detection.iq"),
+#[test]
+fn handles_diagnostics() {
+    let mut builtins = Builtins::new();
+    builtins.register_scalar_with_arity("half", 1, |args| args[0] / 2.0);
+    let engine = iq::Engine::with_builtins(builtins);
+    let check = |expressions: &str| -> Vec<(Span, String, Option<String>)> {
+        engine
+            .check(expressions)
+            .err()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|diagnostic| (diagnostic.span, diagnostic.message, diagnostic.hint))
+            .collect()
+    };
+    let hint = |hint: &str| Some(String::from(hint));
+    let per_pixel = String::from("Scalar statements should have a single value");
+
+    assert!(check("mask m = _.r > 10; [m] | _ => p(0, 0, half(_.r), 0, 0); mean(_.x);").is_empty());
+    assert_eq!(
+        vec![(
+            Span::new(2, 5),
+            String::from("Unknown attribute of a pixel: \"red\""),
+            hint("expected one of y, x, ry, rx, v, u, radius, angle, lum, r, g, b, a")
+        )],
+        check("_.red > 10 => _")
+    );
+    assert_eq!(
+        vec![(
+            Span::new(5, 10),
+            String::from("\"frame\" is a reserved name"),
+            hint("t, frame, frames and meta can't name masks")
+        )],
+        check("mask frame = _.r > 10; [frame] | _ => _")
+    );
+    assert_eq!(
+        vec![(
+            Span::new(0, 7),
+            String::from("Can't compare a pixel with a scalar"),
+            hint("compare an attribute of the pixel instead, like `_.r`")
+        )],
+        check("_ == 10 => _")
+    );
+    assert_eq!(
+        vec![(
+            Span::new(1, 2),
+            String::from("Unknown mask: \"m\""),
+            hint("`m` is defined later, masks have to be defined before they are used")
+        )],
+        check("[m]; mask m = _.r > 1; [m];")
+    );
+    assert_eq!(
+        vec![(
+            Span::new(0, 10),
+            String::from("half() takes 1 argument but 2 were given"),
+            None
+        )],
+        check("half(1, 2) > 0 => _")
+    );
+    assert_eq!(
+        vec![(
+            Span::new(0, 3),
+            per_pixel.clone(),
+            hint("try an aggregate like `mean(...)`")
+        )],
+        check("_.x;")
     );
 
-    assert_eq!(output_ctx.clone(), output_ctx);
+    // Parameters evaluated once per context can't vary between pixels.
+    let uniform = |expressions: &str| {
+        check(expressions)
+            .into_iter()
+            .map(|(span, message, _)| (span, message))
+            .collect::<Vec<_>>()
+    };
+    let per_context = String::from("Parameter should be the same for every pixel");
+    assert_eq!(
+        vec![(Span::new(17, 24), per_context.clone())],
+        uniform("_ => box_blur(_, _.x / 4)")
+    );
+    assert_eq!(
+        vec![(Span::new(3, 13), per_context.clone())],
+        uniform("[0:rand() * 9:2] | _ => _")
+    );
+    assert_eq!(
+        vec![(Span::new(25, 28), per_context)],
+        uniform("mask m = _.r > 1 @ erode(_.y); [m];")
+    );
+    assert!(uniform("_ => alpha_blend(box_blur(_, [].w / 4), _.y / [].h)").is_empty());
+
+    assert_eq!(
+        vec![(
+            Span::new(4, 9),
+            String::from("Slice step should be at least 1, found 0"),
+            None
+        )],
+        check("[0::1 - 1, :]")
+    );
+    // A step which is only known when evaluating selects nothing rather than
+    // panicking.
+    let script = "[::[].w - [].w, :] | _ => color_scale(_, 0.0)";
+    assert!(check(script).is_empty());
+    assert_eq!(
+        0,
+        engine
+            .try_execute(BasicContext::blank(4, 4), String::from(script))
+            .unwrap()
+            .count()
+    );
+    assert_eq!(
+        vec![(
+            Span::new(35, 40),
+            String::from("noise2d() scale should be positive, found -4"),
+            None
+        )],
+        check("_ => p(_.y, _.x, noise2d(_.x, _.y, 0 - 4), 0, 0)")
+    );
+
+    // Every problem is reported, not just the first.
+    let found = check("_.q > 1 => p(0, 0, _.gg, 0, 0); mean(_.x) + _.y;");
+    assert_eq!(
+        vec![Span::new(2, 3), Span::new(21, 23), Span::new(32, 47)],
+        found.iter().map(|(span, _, _)| *span).collect::<Vec<_>>()
+    );
+    assert_eq!(hint("did you mean `g`?"), found[1].2);
+    assert_eq!(per_pixel, found[2].1);
+
+    // Typos are matched against builtin and registered names.
+    let suggestion = |expressions: &str| check(expressions).remove(0).2;
+    assert_eq!(
+        hint("did you mean `color_scale`?"),
+        suggestion("_ => colour_scale(_, 2)")
+    );
+    assert_eq!(hint("did you mean `half`?"), suggestion("hlaf(2) > 1 => _"));
+    assert_eq!(
+        hint("did you mean `dark`?"),
+        suggestion("mask dark = _.r < 9; [drak];")
+    );
+    assert_eq!(
+        hint("did you mean `mirror`?"),
+        suggestion("_ => neighbors(_, 1, 1, mirorr)")
+    );
+    assert_eq!(
+        hint("did you mean `iso`?"),
+        suggestion("meta.isoo > 1 => _")
+    );
+    assert_eq!(
+        hint("expected `(`, `_` or a function call"),
+        suggestion("_ =>> _")
+    );
+
+    let diagnostic = engine
+        .check("_ => _;\n_.gg > 1 => _;")
+        .unwrap_err()
+        .remove(0);
+    assert_eq!((2, 3), diagnostic.line_col("_ => _;\n_.gg > 1 => _;"));
+    assert_eq!(
+        "error: Unknown attribute of a pixel: \"gg\"\n \
+         --> 2:3\n  \
+         |\n\
+         2 | _.gg > 1 => _;\n  \
+         |   ^^\n  \
+         = help: did you mean `g`?",
+        diagnostic.render("_ => _;\n_.gg > 1 => _;")
+    );
+
+    // The fallible entry points return the same diagnostics rather than
+    // panicking.
+    let ctx = image("P1 2 1 0 1");
+    let script = String::from("_.gg > 1 => _");
+    let expected = engine.check(&script).unwrap_err();
+    assert_eq!(
+        expected,
+        engine.try_execute(ctx.clone(), script.clone()).unwrap_err()
+    );
+    assert_eq!(
+        expected,
+        engine
+            .try_evaluate(ctx.clone(), script.clone())
+            .unwrap_err()
+    );
+    assert_eq!(
+        expected,
+        engine
+            .try_animate(ctx.clone(), script.clone(), 2)
+            .unwrap_err()
+    );
+    assert_eq!(
+        expected,
+        engine
+            .try_process_frames(vec![ctx.clone()], script)
+            .unwrap_err()
+    );
+    assert_eq!(
+        ctx.clone(),
+        engine.try_execute(ctx, String::from("_ => _")).unwrap()
+    );
 }
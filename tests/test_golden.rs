@@ -0,0 +1,120 @@
+//! Runs every script in `tests/test_files/scripts` against every image in
+//! `tests/test_files/images` and compares the output with the checked in
+//! golden image in `tests/test_files/golden/<script>/<image>.png`.
+//!
+//! Run with `UPDATE_GOLDEN=1` to write the current outputs as the new golden
+//! images. When an output doesn't match, the output and a heatmap of the
+//! differences are written next to each other under `golden-diffs` in the
+//! cargo target tmp directory.
+
+use image::imageops::FilterType;
+use iq::context::{BasicContext, IqPixel, OverflowPolicy};
+use iq::diff;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The largest difference allowed in any channel of any pixel.
+const TOLERANCE: i64 = 1;
+
+/// Inputs are scaled down to fit this size so goldens stay small.
+const INPUT_SIDE: u32 = 64;
+
+fn test_files() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/test_files")
+}
+
+fn sorted_files(dir: &Path, extension: &str) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == extension))
+        .collect();
+    paths.sort();
+    paths
+}
+
+fn stem(path: &Path) -> String {
+    path.file_stem().unwrap().to_string_lossy().to_string()
+}
+
+fn load_input(path: &Path) -> BasicContext {
+    let img = image::open(path)
+        .unwrap()
+        .resize(INPUT_SIDE, INPUT_SIDE, FilterType::Triangle)
+        .to_rgba8();
+    BasicContext::from_iter(
+        BasicContext::blank(img.height(), img.width()).iter(),
+        |pixel| {
+            let c = img.get_pixel(pixel.x, pixel.y).0;
+            IqPixel {
+                c: [c[0] as i64, c[1] as i64, c[2] as i64, c[3] as i64],
+                ..pixel.clone()
+            }
+        },
+    )
+}
+
+/// Describes how the output differs from the golden image, if any channel of
+/// any pixel is off by more than `TOLERANCE`.
+fn mismatch(expected: &BasicContext, actual: &BasicContext) -> Option<String> {
+    match diff::compare(expected, actual) {
+        Err(message) => Some(message),
+        Ok(comparison) => {
+            let max = comparison.channels.iter().map(|c| c.max).max().unwrap();
+            (max > TOLERANCE).then(|| format!("channels differ by up to {}", max))
+        }
+    }
+}
+
+#[test]
+fn scripts_match_golden_images() {
+    let update = env::var("UPDATE_GOLDEN").is_ok_and(|value| value == "1");
+    let diff_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("golden-diffs");
+    let scripts = sorted_files(&test_files().join("scripts"), "iq");
+    let images = sorted_files(&test_files().join("images"), "png");
+    assert!(!scripts.is_empty() && !images.is_empty());
+    let inputs: Vec<BasicContext> = images.iter().map(|image| load_input(image)).collect();
+
+    let mut failures = vec![];
+    for script in &scripts {
+        let expressions = fs::read_to_string(script).unwrap();
+        let golden_dir = test_files().join("golden").join(stem(script));
+        for (image, input) in images.iter().zip(&inputs) {
+            let name = format!("{}/{}.png", stem(script), stem(image));
+            let golden_path = golden_dir.join(format!("{}.png", stem(image)));
+            let actual = iq::execute(input.clone(), expressions.clone())
+                .with_overflow(OverflowPolicy::default());
+
+            if update {
+                fs::create_dir_all(&golden_dir).unwrap();
+                actual.write(golden_path.to_str().unwrap());
+                continue;
+            }
+            if !golden_path.exists() {
+                failures.push(format!("{}: no golden image", name));
+                continue;
+            }
+
+            let expected = BasicContext::from_path(golden_path.to_str().unwrap());
+            if let Some(mismatch) = mismatch(&expected, &actual) {
+                let out_dir = diff_dir.join(stem(script));
+                fs::create_dir_all(&out_dir).unwrap();
+                let out = |suffix: &str| {
+                    let path = out_dir.join(format!("{}.{}.png", stem(image), suffix));
+                    path.to_str().unwrap().to_string()
+                };
+                actual.write(&out("actual"));
+                diff::heatmap(&expected, &actual).write(&out("diff"));
+                failures.push(format!("{}: {}, see {}", name, mismatch, out("diff")));
+            }
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "Outputs don't match their golden images (rerun with UPDATE_GOLDEN=1 if \
+         the change is intended):\n{}",
+        failures.join("\n")
+    );
+}